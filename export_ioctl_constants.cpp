@@ -18,6 +18,8 @@ int main() {
 
     WRITE_CONST(rust_file, FS_IOC_FIEMAP, "c_ulong");
     WRITE_CONST(rust_file, FIEMAP_FLAG_SYNC, "u32");
+    WRITE_CONST(rust_file, FIEMAP_FLAG_XATTR, "u32");
+    WRITE_CONST(rust_file, FIEMAP_FLAG_CACHE, "u32");
     WRITE_CONST(rust_file, FIEMAP_EXTENT_LAST, "u32");
     WRITE_CONST(rust_file, FIEMAP_EXTENT_UNKNOWN, "u32");
     WRITE_CONST(rust_file, FIEMAP_EXTENT_DELALLOC, "u32");
@@ -30,6 +32,15 @@ int main() {
     WRITE_CONST(rust_file, FIEMAP_EXTENT_MERGED, "u32");
     WRITE_CONST(rust_file, FIEMAP_EXTENT_SHARED, "u32");
 
+    WRITE_CONST(rust_file, FS_IOC_GETFLAGS, "c_ulong");
+    WRITE_CONST(rust_file, FS_IOC_SETFLAGS, "c_ulong");
+    WRITE_CONST(rust_file, FS_SYNC_FL, "i32");
+    WRITE_CONST(rust_file, FS_NODUMP_FL, "i32");
+    WRITE_CONST(rust_file, FS_NOATIME_FL, "i32");
+    WRITE_CONST(rust_file, FS_COMPR_FL, "i32");
+    WRITE_CONST(rust_file, FS_NOCOMP_FL, "i32");
+    WRITE_CONST(rust_file, FS_NOCOW_FL, "i32");
+
     rust_file.close();
     return 0;
 }
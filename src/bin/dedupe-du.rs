@@ -0,0 +1,283 @@
+#![deny(warnings)]
+//! A `du` that knows about shared extents. For each directory it reports the logical size of the
+//! files in it, how much of their storage is used only by files in it, and how much is shared
+//! with files elsewhere. Only FIEMAP is used, so it works on any filesystem that supports it.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use dedupetool::device::filesystem_id;
+use dedupetool::diskblade::serialize_path_lossy;
+use dedupetool::ioctl_fiemap::{ExtentIter, MapFlags};
+use dedupetool::physical::PhysicalIndex;
+
+/// Report logical, exclusive and shared space used by directories.
+///
+/// Sharing is only known between the files scanned, so a directory sharing extents with a file
+/// outside all the given paths counts them as exclusive. Like `du`, files with several hard links
+/// are only counted where they're first found.
+#[derive(Parser)]
+#[clap(name = "dedupe-du", version)]
+struct DedupeDu {
+    /// Only print totals for the given paths.
+    #[clap(short, long, conflicts_with = "max_depth")]
+    summarize: bool,
+    /// Only print directories this many levels below the given paths.
+    #[clap(short = 'd', long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Print sizes in bytes, rather than human readable units.
+    #[clap(long)]
+    bytes: bool,
+    /// Print one JSON object per line, with sizes in bytes.
+    #[clap(long, conflicts_with = "bytes")]
+    json: bool,
+    /// Write out dirty data before mapping files, so delayed allocations have a location.
+    /// Without this, they count as exclusive.
+    #[clap(long)]
+    sync: bool,
+    /// The directories, or files, to report on.
+    #[clap(num_args = 1.., default_value = ".")]
+    paths: Vec<PathBuf>,
+}
+
+/// A directory, or a file given on the command line, and its totals so far.
+struct Entry {
+    path: PathBuf,
+    parent: Option<usize>,
+    depth: usize,
+    usage: Usage,
+}
+
+#[derive(Default, Serialize)]
+struct Usage {
+    /// The sum of the sizes of the files.
+    logical: u64,
+    /// Bytes used only by files under this entry.
+    exclusive: u64,
+    /// Bytes used by files under this entry, and also by files elsewhere.
+    shared: u64,
+}
+
+#[derive(Serialize)]
+struct UsageRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    #[serde(flatten)]
+    usage: &'a Usage,
+}
+
+#[derive(Default)]
+struct Scan {
+    entries: Vec<Entry>,
+    /// Entries in the order `du` prints them, with each directory after what's in it.
+    print_order: Vec<usize>,
+    /// Each file's entry, by the index its extents are owned by.
+    files: Vec<usize>,
+    /// Extents by filesystem, as physical offsets are only comparable within one. btrfs
+    /// subvolumes have `st_dev`s of their own, so this isn't keyed by `st_dev`.
+    filesystems: HashMap<u64, PhysicalIndex<usize>>,
+    /// The filesystem of each `st_dev` seen, so it only has to be looked up once.
+    filesystem_ids: HashMap<u64, u64>,
+    /// The `st_dev` and inode of every file added, so hard links are only counted once.
+    inodes: HashSet<(u64, u64)>,
+    any_failed: bool,
+}
+
+fn main() {
+    let args = DedupeDu::parse();
+    let flags = if args.sync {
+        MapFlags::SYNC
+    } else {
+        MapFlags::NONE
+    };
+
+    let mut scan = Scan::default();
+    for path in &args.paths {
+        scan.visit(path, None, flags);
+    }
+    scan.count_physical();
+
+    let max_depth = if args.summarize {
+        Some(0)
+    } else {
+        args.max_depth
+    };
+    if !args.json {
+        println!(
+            "{:>12} {:>12} {:>12}  Path",
+            "Logical", "Exclusive", "Shared"
+        );
+    }
+    for &entry in &scan.print_order {
+        let entry = &scan.entries[entry];
+        if max_depth.is_some_and(|max| entry.depth > max) {
+            continue;
+        }
+        if args.json {
+            let record = UsageRecord {
+                path: &entry.path,
+                usage: &entry.usage,
+            };
+            match serde_json::to_string(&record) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    eprintln!("Failed to print usage of {}: {}", entry.path.display(), e);
+                    scan.any_failed = true;
+                }
+            }
+        } else {
+            let size = |bytes: u64| match args.bytes {
+                true => bytes.to_string(),
+                false => HumanBytes(bytes).to_string(),
+            };
+            println!(
+                "{:>12} {:>12} {:>12}  {}",
+                size(entry.usage.logical),
+                size(entry.usage.exclusive),
+                size(entry.usage.shared),
+                entry.path.display()
+            );
+        }
+    }
+    if scan.any_failed {
+        std::process::exit(1);
+    }
+}
+
+impl Scan {
+    /// Add [path] and everything under it. Symlinks aren't followed.
+    fn visit(&mut self, path: &Path, parent: Option<usize>, flags: MapFlags) {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return self.failed(path, e),
+        };
+        let index = match parent {
+            Some(parent) if !metadata.is_dir() => parent,
+            _ => {
+                self.entries.push(Entry {
+                    path: path.to_path_buf(),
+                    parent,
+                    depth: parent.map_or(0, |p| self.entries[p].depth + 1),
+                    usage: Usage::default(),
+                });
+                self.entries.len() - 1
+            }
+        };
+        if metadata.is_dir() {
+            let mut children = match std::fs::read_dir(path).and_then(|d| {
+                d.map(|e| e.map(|e| e.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            }) {
+                Ok(children) => children,
+                Err(e) => {
+                    self.failed(path, e);
+                    Vec::new()
+                }
+            };
+            children.sort();
+            for child in children {
+                self.visit(&child, Some(index), flags);
+            }
+        } else if metadata.is_file() {
+            if let Err(e) = self.add_file(path, index, flags) {
+                self.failed(path, e);
+            }
+        }
+        if parent.is_none() || metadata.is_dir() {
+            self.print_order.push(index);
+        }
+    }
+
+    fn add_file(
+        &mut self,
+        path: &Path,
+        entry: usize,
+        flags: MapFlags,
+    ) -> Result<(), std::io::Error> {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        let inode = (metadata.dev(), metadata.ino());
+        if self.inodes.contains(&inode) {
+            return Ok(());
+        }
+        let filesystem = match self.filesystem_ids.get(&metadata.dev()) {
+            Some(filesystem) => *filesystem,
+            None => {
+                let filesystem = filesystem_id(&file)?;
+                self.filesystem_ids.insert(metadata.dev(), filesystem);
+                filesystem
+            }
+        };
+
+        let owner = self.files.len();
+        self.files.push(entry);
+        let index = self.filesystems.entry(filesystem).or_default();
+        // Extents without a location can't be shared with anything. The end of the last block
+        // isn't part of the file, so isn't counted.
+        let mut unlocated = 0;
+        for extent in ExtentIter::new(&file, 0..u64::MAX, flags) {
+            let Some(extent) = extent?.clip(0..metadata.size()) else {
+                continue;
+            };
+            match extent.physical_range() {
+                Some(_) => index.insert(owner, &extent),
+                None => unlocated += extent.length,
+            }
+        }
+        // Only once it's been read, so other links to it are tried if it fails.
+        if metadata.nlink() > 1 {
+            self.inodes.insert(inode);
+        }
+        for ancestor in self.ancestors(entry).collect::<Vec<_>>() {
+            let usage = &mut self.entries[ancestor].usage;
+            usage.logical += metadata.size();
+            usage.exclusive += unlocated;
+        }
+        Ok(())
+    }
+
+    /// Work out exclusive and shared bytes, now that every file has been added. Each piece of
+    /// physical storage is exclusive to the entries holding every file that uses it, and shared
+    /// for entries holding only some of them.
+    fn count_physical(&mut self) {
+        let mut files_under = HashMap::<usize, usize>::new();
+        for index in self.filesystems.values() {
+            index.for_each_segment(|range, owners| {
+                files_under.clear();
+                for owner in owners {
+                    for ancestor in ancestors(&self.entries, self.files[**owner]) {
+                        *files_under.entry(ancestor).or_default() += 1;
+                    }
+                }
+                let length = range.end - range.start;
+                for (&entry, &count) in &files_under {
+                    let usage = &mut self.entries[entry].usage;
+                    if count == owners.len() {
+                        usage.exclusive += length;
+                    } else {
+                        usage.shared += length;
+                    }
+                }
+            });
+        }
+    }
+
+    fn ancestors(&self, entry: usize) -> impl Iterator<Item = usize> + '_ {
+        ancestors(&self.entries, entry)
+    }
+
+    fn failed(&mut self, path: &Path, e: std::io::Error) {
+        eprintln!("Failed to read {}: {}", path.display(), e);
+        self.any_failed = true;
+    }
+}
+
+/// [entry] and every entry above it.
+fn ancestors(entries: &[Entry], entry: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(Some(entry), |&e| entries[e].parent)
+}
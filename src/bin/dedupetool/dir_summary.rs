@@ -0,0 +1,157 @@
+//! Savings broken down by directory, to see which projects or users benefited from a run.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use dedupetool::diskblade::serialize_path_lossy;
+
+use crate::Mode;
+
+/// Bytes saved under each directory at a fixed depth.
+pub struct DirSummary {
+    depth: usize,
+    dirs: HashMap<PathBuf, DirTotals>,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct DirTotals {
+    /// Bytes the kernel reported as de-duplicated in files under this directory.
+    pub bytes_deduped: u64,
+    /// Physical bytes freed, shared out between a group's destinations by how much of each was
+    /// de-duplicated.
+    pub bytes_freed: u64,
+    /// Bytes estimated to be freed, for dry runs.
+    pub estimated_savings: u64,
+    /// Bytes of zeros punched out of files under this directory, by `sparsify`.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub bytes_punched: u64,
+    /// Bytes given storage of their own by `unshare`, or that would be in a dry run.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub bytes_unshared: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize)]
+pub struct DirRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    pub dir: &'a Path,
+    #[serde(flatten)]
+    pub totals: &'a DirTotals,
+}
+
+impl DirSummary {
+    pub fn new(depth: usize) -> DirSummary {
+        DirSummary {
+            depth,
+            dirs: HashMap::new(),
+        }
+    }
+
+    /// The totals for the directory that [file] is counted under.
+    pub fn totals_for(&mut self, file: &Path) -> &mut DirTotals {
+        let dir = self.prefix(file);
+        self.dirs.entry(dir).or_default()
+    }
+
+    /// The first [depth] directories of [file]'s path, or its whole parent if that's shallower.
+    fn prefix(&self, file: &Path) -> PathBuf {
+        let parent = file.parent().unwrap_or(file);
+        let mut normal = 0;
+        parent
+            .components()
+            .take_while(|c| {
+                if let Component::Normal(_) = c {
+                    normal += 1;
+                }
+                normal <= self.depth
+            })
+            .collect()
+    }
+
+    /// Every directory, biggest savings first.
+    pub fn records(&self) -> Vec<DirRecord<'_>> {
+        let mut records = self
+            .dirs
+            .iter()
+            .map(|(dir, totals)| DirRecord { dir, totals })
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| {
+            let key = |r: &DirRecord| {
+                (
+                    r.totals.bytes_freed,
+                    r.totals.estimated_savings,
+                    r.totals.bytes_deduped,
+                    r.totals.bytes_punched,
+                    r.totals.bytes_unshared,
+                )
+            };
+            key(b).cmp(&key(a)).then_with(|| a.dir.cmp(b.dir))
+        });
+        records
+    }
+
+    /// Render the breakdown as a table, in the terms of [mode]. [dry_run] shows estimates instead
+    /// of what was done, and [freed_measured] whether to show the bytes freed.
+    pub fn table(&self, mode: Mode, dry_run: bool, freed_measured: bool) -> String {
+        let (name, column): (_, fn(&DirTotals) -> u64) = match (mode, dry_run) {
+            (Mode::Unshare, true) => ("To unshare", |t| t.bytes_unshared),
+            (Mode::Unshare, false) => ("Unshared", |t| t.bytes_unshared),
+            (_, true) => ("Estimated", |t| t.estimated_savings),
+            (Mode::Dedupe, false) => ("Deduped", |t| t.bytes_deduped),
+            (Mode::Sparsify, false) => ("Punched", |t| t.bytes_punched),
+        };
+        let rows = self
+            .records()
+            .into_iter()
+            .map(|r| {
+                let mut row = vec![
+                    r.dir.display().to_string(),
+                    HumanBytes(column(r.totals)).to_string(),
+                ];
+                if freed_measured {
+                    row.push(HumanBytes(r.totals.bytes_freed).to_string());
+                }
+                row
+            })
+            .collect::<Vec<_>>();
+        let mut header = vec!["Directory", name];
+        if freed_measured {
+            header.push("Freed");
+        }
+        let widths = header
+            .iter()
+            .enumerate()
+            .map(|(i, h)| {
+                rows.iter()
+                    .map(|r| r[i].len())
+                    .chain([h.len()])
+                    .max()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut lines = vec![format_row(&header, &widths)];
+        lines.extend(rows.iter().map(|r| format_row(r, &widths)));
+        lines.join("\n")
+    }
+}
+
+/// Left-align the first column, and right-align the rest.
+fn format_row<S: AsRef<str>>(row: &[S], widths: &[usize]) -> String {
+    row.iter()
+        .zip(widths)
+        .enumerate()
+        .map(|(i, (cell, width))| match i {
+            0 => format!("{:<width$}", cell.as_ref(), width = width),
+            _ => format!("{:>width$}", cell.as_ref(), width = width),
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+        .trim_end()
+        .to_string()
+}
@@ -0,0 +1,259 @@
+//! Log output, to stderr and optionally to a file for unattended runs.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use dedupetool::termhelp::StderrStyle;
+
+use crate::progress::Progress;
+
+/// Target for the end of run summary, which is shown on stderr even when quiet.
+pub const SUMMARY: &str = "summary";
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// `key=value` pairs, one record per line.
+    Logfmt,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Sends records to stderr, without tearing the progress bar, and to the log file if there is one.
+pub struct Logger {
+    stderr_level: LevelFilter,
+    progress: Arc<Progress>,
+    file: Option<LogFile>,
+}
+
+struct LogFile {
+    level: LevelFilter,
+    format: LogFormat,
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl Logger {
+    /// Install the logger. Records at [stderr_level] and above go to stderr. The log file, if
+    /// any, gets at least info level records.
+    pub fn install(
+        stderr_level: LevelFilter,
+        progress: Arc<Progress>,
+        file: Option<(&Path, LogFormat)>,
+    ) -> Result<(), std::io::Error> {
+        let file = match file {
+            Some((path, format)) => Some(LogFile {
+                level: stderr_level.max(LevelFilter::Info),
+                format,
+                writer: Mutex::new(LineWriter::new(File::create(path)?)),
+            }),
+            None => None,
+        };
+        // The summary is always shown, so info records can't be filtered out up front.
+        let max_level = file
+            .as_ref()
+            .map_or(stderr_level, |f| f.level.max(stderr_level))
+            .max(LevelFilter::Info);
+        log::set_boxed_logger(Box::new(Logger {
+            stderr_level,
+            progress,
+            file,
+        }))
+        .map_err(std::io::Error::other)?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    fn stderr_enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.stderr_level
+            || (metadata.target() == SUMMARY && metadata.level() <= Level::Info)
+    }
+
+    fn print_stderr(&self, record: &Record) {
+        let message = match record.level() {
+            Level::Error | Level::Warn => record.args().to_string().error_style().to_string(),
+            Level::Info if record.target() == SUMMARY => {
+                record.args().to_string().success_style().to_string()
+            }
+            Level::Info => record.args().to_string(),
+            level => {
+                let mut line = format!("{} {}: {}", level, record.target(), record.args());
+                let _ = record.key_values().visit(&mut LogfmtFields(&mut line));
+                line.style().dim().to_string()
+            }
+        };
+        self.progress.println(message);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr_enabled(metadata)
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|f| metadata.level() <= f.level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr_enabled(record.metadata()) {
+            self.print_stderr(record);
+        }
+        if let Some(file) = &self.file {
+            if record.level() <= file.level {
+                file.write(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.writer.lock().unwrap().flush();
+        }
+    }
+}
+
+impl LogFile {
+    fn write(&self, record: &Record) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let message = record.args().to_string();
+        let message = console::strip_ansi_codes(&message);
+        let line = match self.format {
+            LogFormat::Logfmt => {
+                let mut line = format!(
+                    "ts={:.3} level={} target={} msg={}",
+                    ts,
+                    record.level().as_str().to_lowercase(),
+                    logfmt_value(record.target()),
+                    logfmt_value(&message)
+                );
+                let _ = record.key_values().visit(&mut LogfmtFields(&mut line));
+                line
+            }
+            LogFormat::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("ts".to_string(), ts.into());
+                object.insert(
+                    "level".to_string(),
+                    record.level().as_str().to_lowercase().into(),
+                );
+                object.insert("target".to_string(), record.target().into());
+                object.insert("msg".to_string(), message.into());
+                let _ = record.key_values().visit(&mut JsonFields(&mut object));
+                serde_json::Value::Object(object).to_string()
+            }
+        };
+        // There's nowhere left to report a failure to write the log.
+        let _ = writeln!(self.writer.lock().unwrap(), "{}", line);
+    }
+}
+
+/// Quote [value] for logfmt, if it needs it.
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{{{:x}}}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Appends ` key=value` for each field. Missing values are left out.
+struct LogfmtFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for LogfmtFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut rendered = None;
+        value.visit(RenderValue(&mut rendered))?;
+        if let Some(rendered) = rendered {
+            let _ = write!(self.0, " {}={}", key, logfmt_value(&rendered));
+        }
+        Ok(())
+    }
+}
+
+/// Renders a field's value as text, or `None` if it is missing.
+struct RenderValue<'a>(&'a mut Option<String>);
+
+impl<'v> VisitValue<'v> for RenderValue<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        *self.0 = Some(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        Ok(())
+    }
+}
+
+/// Inserts each field into a JSON object, keeping numbers and booleans typed.
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = serde_json::Value::Null;
+        value.visit(JsonValue(&mut json))?;
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+struct JsonValue<'a>(&'a mut serde_json::Value);
+
+impl<'v> VisitValue<'v> for JsonValue<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        *self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        *self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+}
@@ -0,0 +1,1981 @@
+#![deny(warnings)]
+
+mod dir_summary;
+mod logging;
+mod metrics;
+mod progress;
+mod report;
+mod sparsify;
+mod unshare;
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, Lines, stdin, StdinLock};
+use std::iter::once;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::linux::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use fclones::config::GroupConfig;
+use fclones::log::StdLog;
+use fclones::FileLen;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use indicatif::{HumanBytes, HumanCount};
+use log::{debug, error, info, log, warn, Level, LevelFilter};
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex, OwnedSemaphorePermit, Semaphore};
+
+use dedupetool::device::{device_id, BlockDevice};
+use dedupetool::diskblade::{FileOffset, FileSectionTarget};
+use dedupetool::ioctl_fideduperange::{dedupe_files, DedupeRequest, DedupeResponse};
+use dedupetool::ioctl_fiemap::{map_extents, Extent, ExtentMap, MapFlags};
+use dedupetool::physical::PhysicalUsage;
+use dedupetool::sched::{load_average, IoClass, Priority};
+use dedupetool::termhelp::StderrStyle;
+use dedupetool::throttle::Throttle;
+
+use crate::dir_summary::DirSummary;
+use crate::logging::{LogFormat, Logger, SUMMARY};
+use crate::metrics::Metrics;
+use crate::progress::Progress;
+use crate::report::{Report, ReportFormat, SummaryRecord};
+use crate::sparsify::{print_sparsify, SparsifyResult, ZeroScan};
+use crate::unshare::{print_unshare, NotEnoughSpace, UnshareResult};
+
+type DedupeResult = Result<Option<DedupeInfo>, DedupeError>;
+type PlanResult = Result<Option<DedupePlan>, DedupeError>;
+
+/// Exit code used when some groups or destinations failed, but not all of them.
+const EXIT_PARTIAL_FAILURE: i32 = 1;
+/// Exit code used for bad arguments or unreadable input. clap uses it for usage errors too.
+const EXIT_BAD_INPUT: i32 = 2;
+/// Exit code used when every group that was processed failed.
+const EXIT_TOTAL_FAILURE: i32 = 3;
+/// Exit code used when every group failed because the filesystem doesn't support de-duping.
+const EXIT_UNSUPPORTED: i32 = 4;
+/// Exit code used when there isn't enough free space to unshare the files asked for.
+const EXIT_NO_SPACE: i32 = 5;
+/// Exit code used when the scheduling priority asked for needs privileges we don't have.
+const EXIT_NOT_PERMITTED: i32 = 6;
+/// Exit code used when the run was stopped by SIGINT or SIGTERM.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Errors from FIDEDUPERANGE that mean the filesystem can't de-dupe at all: `EOPNOTSUPP` from
+/// filesystems that know about it, and `ENOTTY` from ones without the ioctl. `EINVAL` isn't one of
+/// them, as it's also returned for bad ranges and files that aren't regular.
+const UNSUPPORTED_ERRNOS: [i32; 2] = [libc::EOPNOTSUPP, libc::ENOTTY];
+
+/// File section de-duplicator.
+#[derive(Parser)]
+#[clap(
+    name = "dedupetool",
+    version,
+    after_help = "Exit codes:
+  0    Success
+  1    Some groups or destinations failed
+  2    Bad arguments or input
+  3    Every group failed
+  4    Every group failed, because the filesystem doesn't support de-duping
+  5    Not enough free space to unshare the files given to `dedupetool unshare`
+  6    Not permitted to use the `--io-class` or `--cpu-nice` asked for
+  130  Interrupted"
+)]
+struct DedupeTool {
+    /// Maximum concurrent de-dupe calls.
+    #[clap(short, long, default_value = "32")]
+    max_concurrency: usize,
+    /// Maximum concurrent de-dupe calls on a single rotational device.
+    #[clap(long, default_value = "2")]
+    rotational_concurrency: NonZeroUsize,
+    /// Maximum concurrent de-dupe calls on a single non-rotational device.
+    /// Devices that can't be classified are only limited by `--max-concurrency`.
+    #[clap(long, default_value = "32")]
+    non_rotational_concurrency: NonZeroUsize,
+    /// Maximum targets to read ahead while waiting for their device to be available.
+    /// This lets a busy device be skipped over in favor of an idle one.
+    #[clap(long, default_value = "1024")]
+    max_queued: NonZeroUsize,
+    /// The I/O scheduling class to run with, like `ionice -c`.
+    /// `best-effort` uses the lowest priority level within the class.
+    #[clap(long, value_enum)]
+    io_class: Option<IoClassArg>,
+    /// The nice value to run with, like `nice -n`.
+    #[clap(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    cpu_nice: Option<i32>,
+    /// Stop starting new de-dupes while the 1-minute load average is above this.
+    #[clap(long)]
+    pause_when_loadavg_above: Option<f64>,
+    /// Stop starting new de-dupes after running for this long, e.g. `2h` or `1h30m`.
+    /// In-flight de-dupes are allowed to finish.
+    ///
+    /// There's no resume journal, so a later run starts from the first target again. Sections
+    /// an earlier run already shared are found with FIEMAP and skipped cheaply, unless
+    /// `--skip-fiemap` is given.
+    #[clap(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// Stop starting new de-dupes after the kernel has been asked to compare this many bytes,
+    /// e.g. `5TiB`. In-flight de-dupes are allowed to finish.
+    #[clap(long)]
+    max_bytes_compared: Option<FileLen>,
+    /// Read every target up front, then process them in order of estimated savings, biggest
+    /// first. Useful with budgets, so the time is spent where it matters most.
+    #[clap(long)]
+    prioritize: bool,
+    /// Skip targets estimated to save less than this, e.g. `1MiB`.
+    /// The estimate doesn't account for already shared sections when using `--skip-fiemap`.
+    #[clap(long, default_value = "0")]
+    min_savings: FileLen,
+    /// Write a machine-readable report of the run to this file.
+    #[clap(long)]
+    report: Option<PathBuf>,
+    /// The format of the report.
+    #[clap(long, value_enum, default_value = "json", requires = "report")]
+    report_format: ReportFormat,
+    /// At the end of the run, show how much was saved under each directory this many levels
+    /// deep, e.g. `2` for `/home/<user>`. Also included in the report.
+    #[clap(long, value_name = "DEPTH")]
+    summary_by_dir: Option<NonZeroUsize>,
+    /// Write Prometheus metrics to this file at the end of the run, for node_exporter's
+    /// textfile collector. The file is replaced atomically.
+    #[clap(long)]
+    metrics_file: Option<PathBuf>,
+    /// Also write the metrics file this often during the run, e.g. `30s`.
+    #[clap(long, value_parser = parse_interval, requires = "metrics_file")]
+    metrics_interval: Option<Duration>,
+    /// Should the up-front FIEMAP check for already shared sections be skipped?
+    /// This trades size report accuracy for speed. Only for de-duping, as `sparsify` and
+    /// `unshare` need the extents to know what to do.
+    #[clap(long)]
+    skip_fiemap: bool,
+    /// True to run without making changes. Prints what would be de-duplicated, and how much
+    /// space it's estimated to free.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+    /// Maximum bytes per second the kernel is asked to compare, across all de-dupe calls.
+    /// A de-dupe call reads its range from the source and from every destination.
+    /// Accepts units, e.g. `200MiB`.
+    #[clap(long, value_parser = parse_rate)]
+    max_bytes_per_second: Option<FileLen>,
+    /// Maximum de-dupe calls per second.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_iops: Option<u64>,
+    /// Maximum bytes per second read from a single device, as `<PATH>=<RATE>`.
+    /// The path may be a block device, or any file on the device.
+    /// May be given multiple times.
+    #[clap(long, value_name = "PATH=RATE", value_parser = parse_device_limit)]
+    device_max_bytes_per_second: Vec<(u64, FileLen)>,
+    /// Show more detail. Give twice for per-chunk ioctl results.
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Only show warnings, errors and the final summary.
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also write log records to this file, with their fields. Gets at least info level records,
+    /// regardless of `--quiet`.
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    /// The format of the log file.
+    #[clap(long, value_enum, default_value = "logfmt", requires = "log_file")]
+    log_format: LogFormat,
+    /// Indicates how to find the targets to de-dupe, or what else to do.
+    #[clap(subcommand)]
+    subcommand: DeduplicationTargetFinder,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum IoClassArg {
+    Idle,
+    BestEffort,
+}
+
+impl From<IoClassArg> for IoClass {
+    fn from(value: IoClassArg) -> Self {
+        match value {
+            IoClassArg::Idle => IoClass::Idle,
+            IoClassArg::BestEffort => IoClass::BestEffort(7),
+        }
+    }
+}
+
+/// Parses a `<PATH>=<RATE>` pair, resolving the path to its device ID. The throttle resolves
+/// that to the block device, so any file on a btrfs filesystem works too.
+fn parse_device_limit(s: &str) -> Result<(u64, FileLen), String> {
+    let (path, rate) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <PATH>=<RATE>, got '{}'", s))?;
+    let dev = device_id(path).map_err(|e| format!("failed to find device of {}: {}", path, e))?;
+    Ok((dev, parse_rate(rate)?))
+}
+
+/// Parses a positive byte count, with optional units.
+fn parse_rate(s: &str) -> Result<FileLen, String> {
+    match s.parse::<FileLen>() {
+        Ok(FileLen(0)) => Err("rate must be greater than zero".to_string()),
+        Ok(rate) => Ok(rate),
+        Err(e) => Err(format!("invalid rate '{}': {}", s, e)),
+    }
+}
+
+/// Parses a non-zero duration, as [parse_duration] does.
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        Duration::ZERO => Err("interval must be greater than zero".to_string()),
+        interval => Ok(interval),
+    }
+}
+
+/// Parses a duration made of `<number><unit>` parts, e.g. `1h30m`.
+/// The units are `s`, `m`, `h` and `d`, and a bare number is in seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let mut total = 0u64;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("invalid duration '{}'", s));
+        }
+        let value: u64 = rest[..digits]
+            .parse()
+            .map_err(|e| format!("invalid duration '{}': {}", s, e))?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_len] {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            unit => return Err(format!("unknown unit '{}' in duration '{}'", unit, s)),
+        };
+        rest = &rest[unit_len..];
+        total = value
+            .checked_mul(multiplier)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("duration '{}' is too long", s))?;
+    }
+    Ok(Duration::from_secs(total))
+}
+
+#[derive(Subcommand, Default)]
+enum DeduplicationTargetFinder {
+    /// Load files from stdin.
+    #[default]
+    Stdin,
+    /// Find files using `fclones`. Takes the same arguments as `fclones group`.
+    Fclones(Box<GroupConfig>),
+    /// Punch out block-aligned runs of zeros from files, rather than de-duping them.
+    ///
+    /// Files mustn't be written to while they're being sparsified, as zeros written after they
+    /// were read may be punched out along with the data around them.
+    Sparsify {
+        /// The files, or directories of files, to sparsify.
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Give files storage of their own, undoing de-duplication and reflinks.
+    ///
+    /// Only the parts of files that FIEMAP reports as shared are unshared, in place where the
+    /// filesystem supports it (XFS), otherwise by replacing the file with a copy (btrfs). Files
+    /// with other hard links can't be replaced. A copy keeps the file's permissions, owner,
+    /// extended attributes, times and `chattr` flags, but is a new inode, so programs that have
+    /// the file open keep the old one. The free space needed is shown first, and nothing is done
+    /// if a filesystem doesn't have enough. `--min-savings` skips files with less shared than
+    /// that.
+    Unshare {
+        /// The files, or directories of files, to unshare.
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+impl DeduplicationTargetFinder {
+    async fn into_target_iter(
+        self,
+        ctx: &DedupeContext,
+    ) -> Result<Box<dyn Iterator<Item = DeduplicationTarget>>, NotEnoughSpace> {
+        Ok(match self {
+            DeduplicationTargetFinder::Stdin => Box::new(stdin_fdupes_targets()),
+            DeduplicationTargetFinder::Fclones(config) => Box::new(fclones_targets(*config)),
+            DeduplicationTargetFinder::Sparsify { paths } => Box::new(
+                regular_files(&paths, &ctx.progress)
+                    .into_iter()
+                    .map(|path| DeduplicationTarget::Sparsify { path, scan: None }),
+            ),
+            DeduplicationTargetFinder::Unshare { paths } => {
+                Box::new(unshare::unshare_targets(ctx, paths).await?.into_iter())
+            }
+        })
+    }
+
+    fn mode(&self) -> Mode {
+        match self {
+            DeduplicationTargetFinder::Stdin | DeduplicationTargetFinder::Fclones(_) => {
+                Mode::Dedupe
+            }
+            DeduplicationTargetFinder::Sparsify { .. } => Mode::Sparsify,
+            DeduplicationTargetFinder::Unshare { .. } => Mode::Unshare,
+        }
+    }
+}
+
+/// What a run does to its targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Dedupe,
+    Sparsify,
+    Unshare,
+}
+
+/// State shared by every de-dupe task.
+#[derive(Clone)]
+struct DedupeContext {
+    mode: Mode,
+    skip_fiemap: bool,
+    dry_run: bool,
+    min_savings: u64,
+    throttle: Arc<Throttle>,
+    load_gate: Arc<LoadGate>,
+    budget: Arc<Budget>,
+    progress: Arc<Progress>,
+    /// Becomes `true` once the user has asked us to stop.
+    interrupted: watch::Receiver<bool>,
+}
+
+impl DedupeContext {
+    fn is_interrupted(&self) -> bool {
+        *self.interrupted.borrow()
+    }
+
+    /// Whether the physical bytes freed are measured, rather than just estimated or not
+    /// applicable.
+    fn bytes_freed_measured(&self) -> bool {
+        !self.dry_run
+            && match self.mode {
+                Mode::Dedupe => !self.skip_fiemap,
+                Mode::Sparsify => true,
+                Mode::Unshare => false,
+            }
+    }
+}
+
+impl DedupeTool {
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Warn,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
+}
+
+fn main() {
+    let args: DedupeTool = DedupeTool::parse();
+    if args.skip_fiemap && args.subcommand.mode() != Mode::Dedupe {
+        DedupeTool::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--skip-fiemap` only applies to de-duping, not `sparsify` or `unshare`",
+            )
+            .exit();
+    }
+
+    let progress = Arc::new(Progress::new());
+    if let Err(e) = Logger::install(
+        args.log_level(),
+        progress.clone(),
+        args.log_file.as_deref().map(|path| (path, args.log_format)),
+    ) {
+        // The logger isn't there to report this.
+        eprintln!(
+            "{}",
+            format!("Failed to open log file: {}", e).error_style()
+        );
+        exit(EXIT_BAD_INPUT);
+    }
+
+    let priority = Priority {
+        io_class: args.io_class.map(IoClass::from),
+        cpu_nice: args.cpu_nice,
+    };
+    // Threads inherit these, but apply them to the runtime's threads anyway in case some were
+    // created before we got here.
+    if let Err(e) = priority.apply_to_current_thread() {
+        error!(errno = e.raw_os_error(); "Failed to set scheduling priority: {}", e);
+        // Negative nice values need `CAP_SYS_NICE`.
+        exit(match e.raw_os_error() {
+            Some(libc::EPERM | libc::EACCES) => EXIT_NOT_PERMITTED,
+            _ => EXIT_BAD_INPUT,
+        });
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .on_thread_start(move || {
+            // Already known to work from the main thread.
+            let _ = priority.apply_to_current_thread();
+        })
+        .build()
+        .expect("Failed to build runtime")
+        .block_on(run(args, progress));
+}
+
+async fn run(args: DedupeTool, progress: Arc<Progress>) {
+    let mut interrupted = install_interrupt_handler();
+    let ctx = DedupeContext {
+        mode: args.subcommand.mode(),
+        skip_fiemap: args.skip_fiemap,
+        dry_run: args.dry_run,
+        min_savings: args.min_savings.0,
+        throttle: Arc::new(Throttle::new(
+            args.max_bytes_per_second.map(|r| r.0),
+            args.max_iops,
+            args.device_max_bytes_per_second
+                .iter()
+                .map(|(dev, rate)| (*dev, rate.0))
+                .collect(),
+        )),
+        load_gate: Arc::new(LoadGate {
+            max_load: args.pause_when_loadavg_above,
+            paused: Mutex::new(()),
+        }),
+        budget: Arc::new(Budget {
+            // A deadline too far away to represent is as good as none.
+            deadline: args.max_runtime.and_then(|d| Instant::now().checked_add(d)),
+            max_bytes_compared: args.max_bytes_compared.map(|b| b.0),
+            bytes_compared: AtomicU64::new(0),
+        }),
+        progress,
+        interrupted: interrupted.clone(),
+    };
+    let start = Instant::now();
+    let mut tracker = Tracker {
+        by_dir: args
+            .summary_by_dir
+            .map(|depth| DirSummary::new(depth.get())),
+        ..Tracker::default()
+    };
+    if let Some(path) = &args.report {
+        match Report::create(path, args.report_format) {
+            Ok(report) => tracker.report = Some(report),
+            Err(e) => {
+                error!(
+                    path:% = path.display(), errno = e.raw_os_error();
+                    "Failed to create report {}: {}", path.display(), e
+                );
+                exit(EXIT_BAD_INPUT);
+            }
+        }
+    }
+    let tracker = Arc::new(Mutex::new(tracker));
+    let metrics_writer = match (&args.metrics_file, args.metrics_interval) {
+        (Some(path), Some(interval)) => Some(tokio::spawn({
+            let ctx = ctx.clone();
+            let tracker = tracker.clone();
+            let path = path.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    let metrics = render_metrics(&ctx, &*tracker.lock().await, start);
+                    if let Err(e) = metrics::write(&path, metrics).await {
+                        error!(
+                            path:% = path.display(), errno = e.raw_os_error();
+                            "Failed to write metrics file: {}", e
+                        );
+                    }
+                }
+            }
+        })),
+        _ => None,
+    };
+    let concurrency_mutex = Arc::new(Semaphore::new(args.max_concurrency));
+    let queue_mutex = Arc::new(Semaphore::new(args.max_queued.get()));
+    let device_limits = Arc::new(DeviceLimits::new(
+        args.rotational_concurrency.get(),
+        args.non_rotational_concurrency.get(),
+        args.max_concurrency,
+    ));
+    let mut dedupe_futures = FuturesUnordered::new();
+
+    let mut targets = match args.subcommand.into_target_iter(&ctx).await {
+        Ok(targets) => targets,
+        // Already explained, so just finish up with nothing done.
+        Err(NotEnoughSpace) => {
+            tracker.lock().await.not_enough_space = true;
+            Box::new(std::iter::empty())
+        }
+    };
+    let mut bytes_to_compare = None;
+    if args.prioritize {
+        ctx.progress.set_discovery_message("Planning");
+        let planned = plan_targets(&ctx, targets, args.max_concurrency).await;
+        if !ctx.dry_run {
+            bytes_to_compare = planned.iter().map(|t| t.bytes_to_compare()).sum();
+        }
+        targets = Box::new(planned.into_iter());
+    }
+    ctx.progress.start_processing(
+        match targets.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(upper as u64),
+            _ => None,
+        },
+        bytes_to_compare,
+    );
+    let progress_ticker = tokio::spawn({
+        let ctx = ctx.clone();
+        async move {
+            loop {
+                ctx.progress
+                    .update_bytes(ctx.budget.bytes_compared.load(Ordering::Relaxed));
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    });
+    while let Some(target) = targets.next() {
+        if ctx.is_interrupted() {
+            tracker.lock().await.count_remaining(targets);
+            break;
+        }
+        if let Some(reason) = ctx.budget.exhausted() {
+            let mut tracker = tracker.lock().await;
+            tracker.budget_exhausted = Some(reason);
+            tracker.count_remaining(targets);
+            break;
+        }
+        let ctx = ctx.clone();
+        let tracker = tracker.clone();
+        let concurrency_mutex = concurrency_mutex.clone();
+        let device_limits = device_limits.clone();
+        // Avoid over-pulling from the iterator by waiting for the semaphore to be available.
+        let queued = tokio::select! {
+            permit = queue_mutex.clone().acquire_owned() => permit.unwrap(),
+            _ = interrupted.wait_for(|i| *i) => {
+                tracker.lock().await.count_remaining(targets);
+                break;
+            }
+            _ = ctx.budget.deadline_reached() => {
+                let mut tracker = tracker.lock().await;
+                tracker.budget_exhausted = ctx.budget.exhausted();
+                tracker.count_remaining(targets);
+                break;
+            }
+        };
+        ctx.progress.target_found();
+        dedupe_futures.push(tokio::spawn(async move {
+            let _queued = queued;
+            run_target(&ctx, &tracker, &device_limits, concurrency_mutex, target).await;
+            ctx.progress.target_done();
+        }));
+    }
+
+    while let Some(f) = dedupe_futures.next().await {
+        f.expect("Panic in dedupe future");
+    }
+    progress_ticker.abort();
+    if let Some(metrics_writer) = metrics_writer {
+        metrics_writer.abort();
+    }
+    ctx.progress.finish();
+
+    let mut tracker = tracker.lock().await;
+
+    if let Some(path) = &args.metrics_file {
+        if let Err(e) = metrics::write(path, render_metrics(&ctx, &tracker, start)).await {
+            error!(
+                path:% = path.display(), errno = e.raw_os_error();
+                "Failed to write metrics file: {}", e
+            );
+        }
+    }
+
+    if let Some(report) = tracker.report.take() {
+        let summary = SummaryRecord {
+            bytes_deduped: tracker.max_bytes_saved,
+            bytes_freed: ctx.bytes_freed_measured().then_some(tracker.bytes_freed),
+            estimated_savings: (ctx.dry_run && ctx.mode != Mode::Unshare)
+                .then_some(tracker.estimated_savings),
+            bytes_punched: (ctx.mode == Mode::Sparsify).then_some(tracker.bytes_punched),
+            bytes_unshared: (ctx.mode == Mode::Unshare).then_some(tracker.bytes_unshared),
+            any_failed: tracker.any_failed,
+            not_enough_space: (ctx.mode == Mode::Unshare).then_some(tracker.not_enough_space),
+            interrupted: ctx.is_interrupted(),
+            budget_exhausted: tracker.budget_exhausted,
+            targets_remaining: tracker.targets_remaining,
+            input_unread: tracker.input_unread,
+            duration_secs: start.elapsed().as_secs_f64(),
+            by_dir: tracker.by_dir.as_ref().map(|d| d.records()),
+        };
+        if let Err(e) = report.finish(summary).await {
+            error!(errno = e.raw_os_error(); "Failed to write report: {}", e);
+        }
+    }
+
+    if let Some(by_dir) = &tracker.by_dir {
+        info!(
+            target: SUMMARY,
+            "Savings by directory:\n{}",
+            by_dir.table(ctx.mode, ctx.dry_run, ctx.bytes_freed_measured())
+        );
+    }
+    print_failures(&tracker.failures);
+
+    if ctx.is_interrupted() {
+        log_totals(&ctx, &tracker);
+        exit(EXIT_INTERRUPTED);
+    }
+
+    if let Some(reason) = tracker.budget_exhausted {
+        warn!(
+            target: SUMMARY, budget = reason, targets_remaining = tracker.targets_remaining,
+            input_unread = tracker.input_unread;
+            "Stopped early because the {} budget ran out, {} targets were not processed{}.",
+            reason, HumanCount(tracker.targets_remaining),
+            if tracker.input_unread { ", and the rest of the input wasn't read" } else { "" }
+        );
+    }
+
+    log_totals(&ctx, &tracker);
+    if ctx.bytes_freed_measured() {
+        info!(
+            target: SUMMARY, bytes_freed = tracker.bytes_freed;
+            "Actually freed {} total.", HumanBytes(tracker.bytes_freed)
+        );
+    }
+
+    if let Some(code) = tracker.exit_code() {
+        exit(code);
+    }
+}
+
+/// Log what the run did in total, or would have for a dry run, in the terms of its [Mode].
+fn log_totals(ctx: &DedupeContext, tracker: &Tracker) {
+    let (level, end) = match (ctx.is_interrupted(), ctx.dry_run) {
+        (true, _) => (Level::Warn, " before being interrupted."),
+        (false, true) => (Level::Info, "."),
+        (false, false) => (Level::Info, "!"),
+    };
+    match (ctx.mode, ctx.dry_run) {
+        (Mode::Dedupe | Mode::Sparsify, true) => log!(
+            target: SUMMARY, level, estimated_savings = tracker.estimated_savings;
+            "Estimated to save up to {} total{}", HumanBytes(tracker.estimated_savings), end
+        ),
+        (Mode::Dedupe, false) => log!(
+            target: SUMMARY, level, bytes_deduped = tracker.max_bytes_saved;
+            "Saved up to {} total{}", HumanBytes(tracker.max_bytes_saved), end
+        ),
+        (Mode::Sparsify, false) => log!(
+            target: SUMMARY, level, bytes_punched = tracker.bytes_punched;
+            "Punched out {} of zeros total{}", HumanBytes(tracker.bytes_punched), end
+        ),
+        (Mode::Unshare, true) => log!(
+            target: SUMMARY, level, bytes_unshared = tracker.bytes_unshared;
+            "Would unshare {} total{}", HumanBytes(tracker.bytes_unshared), end
+        ),
+        (Mode::Unshare, false) => log!(
+            target: SUMMARY, level, bytes_unshared = tracker.bytes_unshared;
+            "Unshared {} total{}", HumanBytes(tracker.bytes_unshared), end
+        ),
+    }
+}
+
+fn render_metrics(ctx: &DedupeContext, tracker: &Tracker, start: Instant) -> String {
+    Metrics {
+        tracker,
+        bytes_compared: ctx.budget.bytes_compared.load(Ordering::Relaxed),
+        duration: start.elapsed(),
+        bytes_freed_measured: ctx.bytes_freed_measured(),
+        dry_run: ctx.dry_run,
+    }
+    .render()
+}
+
+/// Wait for permission to start [target], then de-dupe it.
+async fn run_target(
+    ctx: &DedupeContext,
+    tracker: &Mutex<Tracker>,
+    device_limits: &DeviceLimits,
+    concurrency_mutex: Arc<Semaphore>,
+    target: DeduplicationTarget,
+) {
+    // Wait for the device first, so that we don't hold a global permit while it's busy.
+    let _device_permit = device_limits.acquire(&target).await;
+    let _permit = concurrency_mutex.acquire_owned().await.unwrap();
+    ctx.load_gate.wait(ctx).await;
+    if ctx.is_interrupted() {
+        tracker.lock().await.targets_remaining += 1;
+        return;
+    }
+    if let Some(reason) = ctx.budget.exhausted() {
+        let mut tracker = tracker.lock().await;
+        tracker.budget_exhausted = Some(reason);
+        tracker.targets_remaining += 1;
+        return;
+    }
+    if let DeduplicationTarget::Sparsify { path, scan } = target {
+        let result = sparsify::process_sparsify(ctx, path, scan).await;
+        tracker.lock().await.record_sparsify(result);
+        return;
+    }
+    if let DeduplicationTarget::Unshare(path) = target {
+        let result = unshare::process_unshare(ctx, path).await;
+        tracker.lock().await.record_unshare(result);
+        return;
+    }
+    if ctx.dry_run {
+        let result = process_plan(ctx, target).await;
+        tracker.lock().await.record_plan(result);
+        return;
+    }
+    let result = process_dedupe(ctx, target).await;
+    tracker.lock().await.record_result(result);
+}
+
+/// Estimate the savings of every target, and order them from biggest to smallest.
+/// Targets below the minimum savings are dropped. Targets that fail to be estimated are put
+/// last, so that the error is still reported when they're processed. Once interrupted or out of
+/// budget, the rest aren't estimated, but are kept so they're counted as remaining.
+async fn plan_targets(
+    ctx: &DedupeContext,
+    targets: Box<dyn Iterator<Item = DeduplicationTarget>>,
+    concurrency: usize,
+) -> Vec<DeduplicationTarget> {
+    let mut estimated = futures::stream::iter(targets)
+        .map(|target| async move {
+            if ctx.is_interrupted() || ctx.budget.exhausted().is_some() {
+                return (Estimate::Unplanned, target);
+            }
+            let estimated = estimate_target(ctx, target).await;
+            ctx.progress.target_discovered();
+            estimated
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let total = estimated.len();
+    estimated
+        .retain(|(estimate, _)| !matches!(estimate, Estimate::Savings(s) if *s < ctx.min_savings));
+    let skipped = total - estimated.len();
+    estimated.sort_by_key(|(estimate, _)| Reverse(*estimate));
+    let estimated_savings = estimated
+        .iter()
+        .map(|(e, _)| match e {
+            Estimate::Savings(savings) => *savings,
+            _ => 0,
+        })
+        .sum();
+    let unplanned = estimated
+        .iter()
+        .filter(|(e, _)| *e == Estimate::Unplanned)
+        .count();
+    info!(
+        targets = estimated.len() - unplanned, estimated_savings;
+        "Planned {} targets, estimated to save up to {}.",
+        HumanCount((estimated.len() - unplanned) as u64), HumanBytes(estimated_savings)
+    );
+    if skipped > 0 {
+        info!(
+            targets = skipped, min_savings = ctx.min_savings;
+            "Skipping {} targets estimated to save less than {}.",
+            HumanCount(skipped as u64), HumanBytes(ctx.min_savings)
+        );
+    }
+    if unplanned > 0 {
+        warn!(
+            targets = unplanned;
+            "Stopped planning early, {} targets weren't estimated.", HumanCount(unplanned as u64)
+        );
+    }
+    estimated.into_iter().map(|(_, target)| target).collect()
+}
+
+/// How a target came out of planning. Targets are processed from the greatest to the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Estimate {
+    /// Planning stopped before getting to it.
+    Unplanned,
+    /// It couldn't be estimated.
+    Failed,
+    Savings(u64),
+}
+
+/// Estimate the savings of [target]. Groups of files are planned in full, and come back as
+/// [DeduplicationTarget::Planned] so their extents aren't read again when they're processed.
+/// Likewise, files to sparsify come back with the zeros that were found in them.
+async fn estimate_target(
+    ctx: &DedupeContext,
+    target: DeduplicationTarget,
+) -> (Estimate, DeduplicationTarget) {
+    let estimate = match &target {
+        DeduplicationTarget::Files(files) => {
+            return match plan_dedupe(ctx, target.clone()).await {
+                Ok(plan) => (
+                    Estimate::Savings(plan.as_ref().map_or(0, |p| p.estimated_savings)),
+                    DeduplicationTarget::Planned {
+                        files: files.clone(),
+                        plan: plan.map(Box::new),
+                    },
+                ),
+                Err(_) => (Estimate::Failed, target),
+            };
+        }
+        DeduplicationTarget::Planned { plan, .. } => {
+            Ok(plan.as_ref().map_or(0, |p| p.estimated_savings))
+        }
+        DeduplicationTarget::Sparsify { path, scan: None } => {
+            return match sparsify::scan_sparsify(ctx, path).await {
+                Ok(scan) => (
+                    Estimate::Savings(scan.bytes_zero()),
+                    DeduplicationTarget::Sparsify {
+                        path: path.clone(),
+                        scan: Some(Box::new(scan)),
+                    },
+                ),
+                Err(_) => (Estimate::Failed, target),
+            };
+        }
+        DeduplicationTarget::Sparsify {
+            scan: Some(scan), ..
+        } => Ok(scan.bytes_zero()),
+        DeduplicationTarget::Unshare(path) => unshare::estimate_unshare(path).await,
+    };
+    match estimate {
+        Ok(savings) => (Estimate::Savings(savings), target),
+        Err(_) => (Estimate::Failed, target),
+    }
+}
+
+/// Listens for SIGINT and SIGTERM. The first one flips the returned receiver to `true`, so that
+/// no new work is started, and the second one exits immediately.
+fn install_interrupt_handler() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+        warn!(
+            "Interrupted, letting in-flight de-dupes finish. Interrupt again to abort immediately."
+        );
+        sender.send_replace(true);
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+        warn!("Aborted!");
+        exit(EXIT_INTERRUPTED);
+    });
+    receiver
+}
+
+/// Limits on how much work a run may start.
+struct Budget {
+    deadline: Option<Instant>,
+    max_bytes_compared: Option<u64>,
+    /// Bytes the kernel has been asked to compare so far, counted once per destination.
+    bytes_compared: AtomicU64,
+}
+
+impl Budget {
+    /// Returns the name of the budget that ran out, if any has.
+    fn exhausted(&self) -> Option<&'static str> {
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Some("runtime");
+        }
+        if self
+            .max_bytes_compared
+            .is_some_and(|max| self.bytes_compared.load(Ordering::Relaxed) >= max)
+        {
+            return Some("bytes compared");
+        }
+        None
+    }
+
+    /// Completes once the deadline has passed, or never if there is no deadline.
+    async fn deadline_reached(&self) {
+        match self.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Holds back new de-dupes while the system is busy.
+struct LoadGate {
+    max_load: Option<f64>,
+    /// Held by whichever task is waiting for the load to drop, so only one task polls it.
+    paused: Mutex<()>,
+}
+
+impl LoadGate {
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Wait until the load average is below the limit, or we're interrupted.
+    async fn wait(&self, ctx: &DedupeContext) {
+        let Some(max_load) = self.max_load else {
+            return;
+        };
+        let _paused = self.paused.lock().await;
+        let mut interrupted = ctx.interrupted.clone();
+        let mut logged = false;
+        while !ctx.is_interrupted() {
+            let load = match load_average() {
+                Ok(load) => load,
+                Err(e) => {
+                    error!(errno = e.raw_os_error(); "Not pausing for load: {}", e);
+                    break;
+                }
+            };
+            if load <= max_load {
+                if logged {
+                    info!(load; "Load average is {:.2}, resuming.", load);
+                }
+                break;
+            }
+            if !logged {
+                info!(
+                    load, max_load;
+                    "Load average is {:.2}, pausing until it's below {:.2}.", load, max_load
+                );
+                logged = true;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Self::POLL_INTERVAL) => {}
+                _ = interrupted.wait_for(|i| *i) => {}
+            }
+        }
+    }
+}
+
+/// Per-device concurrency limits. Each device gets its own semaphore, sized by its class.
+struct DeviceLimits {
+    rotational: usize,
+    non_rotational: usize,
+    unknown: usize,
+    /// Semaphores keyed by block device, shared by every filesystem device backed by it.
+    devices: std::sync::Mutex<HashMap<BlockDevice, Arc<Semaphore>>>,
+}
+
+impl DeviceLimits {
+    fn new(rotational: usize, non_rotational: usize, unknown: usize) -> DeviceLimits {
+        DeviceLimits {
+            rotational,
+            non_rotational,
+            unknown,
+            devices: Default::default(),
+        }
+    }
+
+    /// Wait for a permit for the device that [target]'s source lives on.
+    /// Returns `None` if the device can't be determined, the error will surface when
+    /// de-duplicating instead.
+    async fn acquire(&self, target: &DeduplicationTarget) -> Option<OwnedSemaphorePermit> {
+        let st_dev = tokio::fs::metadata(target.files().first()?)
+            .await
+            .ok()?
+            .st_dev();
+        let device = tokio::task::spawn_blocking(move || BlockDevice::resolve(st_dev))
+            .await
+            .expect("failed to spawn blocking");
+        let semaphore = self
+            .devices
+            .lock()
+            .unwrap()
+            .entry(device)
+            .or_insert_with(|| {
+                Arc::new(Semaphore::new(match device.rotational {
+                    Some(true) => self.rotational,
+                    Some(false) => self.non_rotational,
+                    None => self.unknown,
+                }))
+            })
+            .clone();
+        Some(semaphore.acquire_owned().await.unwrap())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DeduplicationTarget {
+    Files(Vec<PathBuf>),
+    /// A group of files that `--prioritize` has already planned. The plan may be `None` if
+    /// there was nothing worth doing. The files are planned again if they've changed since.
+    Planned {
+        files: Vec<PathBuf>,
+        plan: Option<Box<DedupePlan>>,
+    },
+    /// A single file to punch runs of zeros out of. `--prioritize` fills in [scan] with the
+    /// zeros it found, so the file isn't read again if it hasn't changed since.
+    Sparsify {
+        path: PathBuf,
+        scan: Option<Box<ZeroScan>>,
+    },
+    /// A single file to give storage of its own.
+    Unshare(PathBuf),
+}
+
+impl DeduplicationTarget {
+    fn files(&self) -> &[PathBuf] {
+        match self {
+            DeduplicationTarget::Files(files) | DeduplicationTarget::Planned { files, .. } => files,
+            DeduplicationTarget::Sparsify { path, .. } | DeduplicationTarget::Unshare(path) => {
+                std::slice::from_ref(path)
+            }
+        }
+    }
+
+    /// Bytes processing this will compare, if it's been planned.
+    fn bytes_to_compare(&self) -> Option<u64> {
+        match self {
+            DeduplicationTarget::Planned { plan, .. } => {
+                Some(plan.as_ref().map_or(0, |plan| plan.bytes_to_compare()))
+            }
+            DeduplicationTarget::Sparsify {
+                scan: Some(scan), ..
+            } => Some(scan.bytes_read),
+            _ => None,
+        }
+    }
+}
+
+fn fclones_targets(config: GroupConfig) -> impl Iterator<Item = DeduplicationTarget> {
+    let groups = fclones::group_files(&config, &StdLog::new()).unwrap_or_else(|e| {
+        error!("Failed to group files: {}", e);
+        exit(EXIT_BAD_INPUT);
+    });
+    groups.into_iter().map(|g| {
+        DeduplicationTarget::Files(g.files.into_iter().map(|f| f.path.to_path_buf()).collect())
+    })
+}
+
+/// Every regular file under [paths], in name order. Symlinks aren't followed.
+fn regular_files(paths: &[PathBuf], progress: &Progress) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        walk(path, &mut files, progress);
+    }
+    files
+}
+
+fn walk(path: &Path, files: &mut Vec<PathBuf>, progress: &Progress) {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!(
+                path:% = path.display(), errno = e.raw_os_error();
+                "Failed to read {}: {}", path.display(), e
+            );
+            return;
+        }
+    };
+    if metadata.is_file() {
+        files.push(path.to_path_buf());
+        progress.target_discovered();
+        return;
+    }
+    if !metadata.is_dir() {
+        return;
+    }
+    let mut entries = match std::fs::read_dir(path).and_then(|d| {
+        d.map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+    }) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!(
+                path:% = path.display(), errno = e.raw_os_error();
+                "Failed to read directory {}: {}", path.display(), e
+            );
+            return;
+        }
+    };
+    entries.sort();
+    for entry in entries {
+        walk(&entry, files, progress);
+    }
+}
+
+fn stdin_fdupes_targets() -> impl Iterator<Item = DeduplicationTarget> {
+    struct Iter {
+        iter: Lines<StdinLock<'static>>,
+        dedup_lines: Vec<String>,
+    }
+
+    impl Iterator for Iter {
+        type Item = Vec<PathBuf>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            for line_res in self.iter.by_ref() {
+                let line = match line_res {
+                    Ok(l) => l.trim_end().to_owned(),
+                    Err(e) => {
+                        error!(errno = e.raw_os_error(); "Failed to read from stdin: {}", e);
+                        exit(EXIT_BAD_INPUT);
+                    }
+                };
+                if line.is_empty() {
+                    if self.dedup_lines.len() > 1 {
+                        return Some(self.dedup_lines.drain(..).map(PathBuf::from).collect());
+                    }
+                    continue;
+                }
+                self.dedup_lines.push(line);
+            }
+            (self.dedup_lines.len() > 1)
+                .then(|| self.dedup_lines.drain(..).map(PathBuf::from).collect())
+        }
+    }
+
+    Iter {
+        iter: stdin().lock().lines(),
+        dedup_lines: Vec::new(),
+    }
+    .map(DeduplicationTarget::Files)
+}
+
+async fn process_dedupe(ctx: &DedupeContext, target: DeduplicationTarget) -> DedupeResult {
+    internal_process_dedupe(ctx, target.clone())
+        .await
+        .map_err(|e| DedupeError { target, source: e })
+}
+
+async fn process_plan(ctx: &DedupeContext, target: DeduplicationTarget) -> PlanResult {
+    plan_dedupe(ctx, target.clone())
+        .await
+        .map_err(|e| DedupeError { target, source: e })
+}
+
+/// What would be done to de-duplicate a target.
+#[derive(Debug, Clone)]
+struct DedupePlan {
+    /// Every section of the target, including ones that are already shared.
+    all_sections: FileSectionTarget,
+    /// The sections to de-dupe. The first one is the source.
+    target: FileSectionTarget,
+    /// Physical bytes used by all sections, if measured.
+    physical_before: Option<u64>,
+    estimated_savings: u64,
+    /// The size and modification time of each file when it was planned.
+    file_states: HashMap<PathBuf, (u64, (i64, i64))>,
+}
+
+impl DedupePlan {
+    /// Bytes the kernel will be asked to compare: the source against each destination.
+    fn bytes_to_compare(&self) -> u64 {
+        self.target.length * (self.target.offsets.len() as u64 - 1)
+    }
+
+    /// Whether every file still looks like it did when it was planned.
+    async fn is_current(&self) -> bool {
+        for (path, state) in &self.file_states {
+            match file_state(path).await {
+                Ok(current) if current == *state => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// The size and modification time of [path].
+async fn file_state(path: &Path) -> Result<(u64, (i64, i64)), std::io::Error> {
+    let metadata = tokio::fs::metadata(path).await?;
+    Ok((
+        metadata.len(),
+        (metadata.st_mtime(), metadata.st_mtime_nsec()),
+    ))
+}
+
+/// Work out what to de-dupe for [target], without changing anything.
+/// Returns `None` if there is nothing worth doing.
+async fn plan_dedupe(
+    ctx: &DedupeContext,
+    target: DeduplicationTarget,
+) -> Result<Option<DedupePlan>, std::io::Error> {
+    let files = match target {
+        DeduplicationTarget::Files(files) => files,
+        DeduplicationTarget::Planned { plan: None, .. } => return Ok(None),
+        // Planned earlier with the same settings, so the plan is used as is if the files haven't
+        // changed. Anything shared since then is found out by the kernel when de-duping.
+        DeduplicationTarget::Planned {
+            files,
+            plan: Some(plan),
+        } => {
+            if plan.is_current().await {
+                return Ok(Some(*plan));
+            }
+            debug!(
+                files:? = files;
+                "Files changed since they were planned, planning them again"
+            );
+            files
+        }
+        DeduplicationTarget::Sparsify { .. } | DeduplicationTarget::Unshare(_) => {
+            unreachable!("only groups of files are de-duped")
+        }
+    };
+    // Before reading anything, so changes made while planning are noticed later.
+    let mut file_states = HashMap::with_capacity(files.len());
+    for path in &files {
+        file_states.insert(path.clone(), file_state(path).await?);
+    }
+    // Reduce target to FileSectionTarget only.
+    let mut target = resolve_file_sections(files).await?;
+    let extents = if ctx.skip_fiemap {
+        None
+    } else {
+        read_section_extents(&target).await?
+    };
+    let estimated_savings = estimate_savings(&target, extents.as_deref());
+    if estimated_savings < ctx.min_savings {
+        return Ok(None);
+    }
+    // Keep every section around, so the space they use can be measured again afterwards.
+    let all_sections = target.clone();
+    let physical_before = extents.as_ref().map(|e| physical_usage(&target, e));
+    if let Some(extents) = &extents {
+        remove_already_shared_file_sections(&mut target, extents);
+    }
+
+    if target.offsets.len() < 2 {
+        // There are no files to deduplicate.
+        return Ok(None);
+    }
+    Ok(Some(DedupePlan {
+        all_sections,
+        target,
+        physical_before,
+        estimated_savings,
+        file_states,
+    }))
+}
+
+async fn internal_process_dedupe(
+    ctx: &DedupeContext,
+    target: DeduplicationTarget,
+) -> Result<Option<DedupeInfo>, std::io::Error> {
+    let start = Instant::now();
+    let Some(plan) = plan_dedupe(ctx, target).await? else {
+        return Ok(None);
+    };
+    ctx.progress.bytes_found(plan.bytes_to_compare());
+    let DedupePlan {
+        all_sections,
+        target,
+        physical_before,
+        ..
+    } = plan;
+    let (first, rest) = target.offsets.split_first().unwrap();
+
+    let first_file = tokio::fs::File::open(&first.file()).await?.into_std().await;
+
+    // 'static-ify first & rest by cloning them
+    let src_range = first.offset()..(first.offset() + target.length);
+    let rest = Vec::from(rest);
+    let ctx = ctx.clone();
+    let ioctl_start = Instant::now();
+    let responses = tokio::task::spawn_blocking(move || {
+        // Opening and scanning every destination for holes can take a while, so don't start
+        // if interrupted while this was being planned.
+        if ctx.is_interrupted() {
+            return Ok(HashMap::new());
+        }
+        let src_dev = first_file.metadata()?.st_dev();
+        let mut dest_devs = HashMap::<FileOffset, u64>::new();
+        let dest_reqs = rest
+            .into_iter()
+            .map(|file| {
+                if !ctx.throttle.is_unlimited() {
+                    dest_devs.insert(file.clone(), device_id(file.file())?);
+                }
+                let request = DedupeRequest::new(file.file(), file.offset());
+                Ok((file, request))
+            })
+            .collect::<Result<HashMap<FileOffset, DedupeRequest>, std::io::Error>>()?;
+        dedupe_files(&first_file, src_range, dest_reqs, |chunk| {
+            // Finish the current chunk when interrupted, but don't start any more.
+            if ctx.is_interrupted() {
+                return false;
+            }
+            let len = chunk.src_range.end - chunk.src_range.start;
+            if !ctx.throttle.is_unlimited() {
+                let reads = once((src_dev, len))
+                    .chain(chunk.dests.iter().map(|d| (dest_devs[*d], len)))
+                    .collect::<Vec<_>>();
+                // Waiting on the throttle may take a while, so stop waiting when interrupted.
+                if !ctx.throttle.charge(&reads, || ctx.is_interrupted()) {
+                    return false;
+                }
+            }
+            ctx.budget
+                .bytes_compared
+                .fetch_add(len * chunk.dests.len() as u64, Ordering::Relaxed);
+            true
+        })
+    })
+    .await
+    .expect("failed to spawn blocking")?;
+    let ioctl_duration = ioctl_start.elapsed();
+
+    let physical_after = match physical_before {
+        // Failing to measure doesn't undo the de-dupe, so don't fail the group for it.
+        Some(_) => read_section_extents(&all_sections)
+            .await
+            .ok()
+            .flatten()
+            .map(|e| physical_usage(&all_sections, &e)),
+        None => None,
+    };
+
+    let mut offsets_errored = HashMap::<FileOffset, std::io::Error>::new();
+    let mut offsets_affected = HashSet::<FileOffset>::new();
+    let mut bytes_deduped_by_offset = HashMap::<FileOffset, u64>::new();
+    let mut total_bytes_saved = 0;
+    let mut differs = 0;
+    let mut source_holes = Vec::new();
+
+    for (file, response_vec) in responses {
+        for response in response_vec {
+            match response {
+                DedupeResponse::RangeSame { bytes_deduped } => {
+                    if bytes_deduped > 0 {
+                        offsets_affected.insert(file.clone());
+                        *bytes_deduped_by_offset.entry(file.clone()).or_default() += bytes_deduped;
+                        total_bytes_saved += bytes_deduped;
+                    }
+                }
+                DedupeResponse::Error(e) => {
+                    offsets_errored.insert(file.clone(), e);
+                }
+                DedupeResponse::RangeDiffers => {
+                    differs += 1;
+                }
+                DedupeResponse::SourceHole { range } => {
+                    source_holes.push((file.clone(), range));
+                }
+            }
+        }
+    }
+
+    Ok(Some(DedupeInfo {
+        size: target.length,
+        offset_targeted: first.clone(),
+        offsets_errored,
+        offsets_affected: offsets_affected.into_iter().collect(),
+        bytes_deduped_by_offset,
+        total_bytes_saved,
+        differs,
+        source_holes,
+        physical_before,
+        physical_after,
+        duration: start.elapsed(),
+        ioctl_duration,
+    }))
+}
+
+async fn resolve_file_sections(files: Vec<PathBuf>) -> Result<FileSectionTarget, std::io::Error> {
+    if files.is_empty() {
+        return Ok(FileSectionTarget {
+            length: 0,
+            offsets: Vec::new(),
+        });
+    }
+    // Make an assumption that all files are the same size
+    let size = tokio::fs::metadata(&files[0]).await?.len();
+
+    let mut offsets = Vec::with_capacity(files.len());
+    for file in files {
+        // Fail the group for a missing file, rather than panicking in `FileOffset::new`.
+        let file = tokio::fs::canonicalize(&file).await?;
+        offsets.push(FileOffset::new(file, 0));
+    }
+    Ok(FileSectionTarget {
+        length: size,
+        offsets,
+    })
+}
+
+/// Read the extents of each section in [target], in the same order as its offsets. Returns
+/// `None` if a file kept changing while it was read, in which case the group is de-duped
+/// without knowing what's shared already, as with `--skip-fiemap`.
+async fn read_section_extents(
+    target: &FileSectionTarget,
+) -> Result<Option<Vec<Vec<Extent>>>, std::io::Error> {
+    let size = target.length;
+    let mut all_extents = Vec::with_capacity(target.offsets.len());
+    for section in &target.offsets {
+        let offset = section.offset();
+        let f = tokio::fs::File::open(&section.file())
+            .await?
+            .into_std()
+            .await;
+        let map = tokio::task::spawn_blocking(move || {
+            map_extents(&f, offset..(offset + size), MapFlags::NONE)
+        })
+        .await
+        .expect("failed to spawn blocking")?;
+        match map {
+            ExtentMap::Complete(extents) => all_extents.push(extents),
+            ExtentMap::Hole => all_extents.push(Vec::new()),
+            ExtentMap::FileChanged => {
+                debug!(
+                    path:% = section.file().display();
+                    "File changed while reading its extents, not checking what's already shared"
+                );
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(all_extents))
+}
+
+/// Estimate how many bytes de-duplicating [target] would free. Without [extents], nothing is
+/// assumed to be shared already.
+fn estimate_savings(target: &FileSectionTarget, extents: Option<&[Vec<Extent>]>) -> u64 {
+    let Some(extents) = extents else {
+        return target.length * (target.offsets.len() as u64).saturating_sub(1);
+    };
+    // Once de-duplicated, only the source's copy remains. Counting what it really uses, rather
+    // than its length, keeps sparse files from looking like they'd save nothing.
+    let mut source = PhysicalUsage::new();
+    if let (Some(section), Some(section_extents)) = (target.offsets.first(), extents.first()) {
+        source.add_extents(
+            section_extents,
+            section.offset()..(section.offset() + target.length),
+        );
+    }
+    physical_usage(target, extents).saturating_sub(source.total())
+}
+
+/// Count the physical bytes used by the sections in [target], counting shared ranges once.
+fn physical_usage(target: &FileSectionTarget, extents: &[Vec<Extent>]) -> u64 {
+    let mut usage = PhysicalUsage::new();
+    for (section, section_extents) in target.offsets.iter().zip(extents) {
+        usage.add_extents(
+            section_extents,
+            section.offset()..(section.offset() + target.length),
+        );
+    }
+    usage.total()
+}
+
+/// Drop all but one of the sections that already use exactly the same storage.
+///
+/// Sections are compared by their physical extents. Logical offsets and lengths aren't enough,
+/// as separate copies of a file often have the same layout, and would all be dropped.
+fn remove_already_shared_file_sections(target: &mut FileSectionTarget, extents: &[Vec<Extent>]) {
+    // Map of Vec<(physical offset, len)> to Vec of offsets
+    let mut physical_extent_buckets = HashMap::<Vec<(u64, u64)>, Vec<FileOffset>>::new();
+    for (section, section_extents) in target.offsets.iter().zip(extents) {
+        // Extents without a location (e.g. delayed allocations) can't be shared yet.
+        let Some(physical_extents) = section_extents
+            .iter()
+            .map(|ext| ext.physical_range().map(|r| (r.start, r.end - r.start)))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        physical_extent_buckets
+            .entry(physical_extents)
+            .or_default()
+            .push(section.clone());
+    }
+    let Some(biggest_vec) = physical_extent_buckets.values().max_by_key(|v| v.len()) else {
+        return;
+    };
+
+    if biggest_vec.len() == 1 {
+        // There are no shared groups, existing vec is good
+    } else if biggest_vec.len() == target.offsets.len() {
+        // Everything is shared! Empty the offsets list!
+        target.offsets.clear();
+    } else {
+        // Some offsets are shared, take the biggest vec and remove all but 1 of them from the offsets
+        let (_, rest) = biggest_vec.split_first().unwrap();
+        let remove_these: HashSet<_> = rest.iter().collect();
+        target.offsets.retain(|x| !remove_these.contains(x));
+    }
+}
+
+#[derive(Default)]
+struct Tracker {
+    max_bytes_saved: u64,
+    /// Bytes estimated to be freed, for dry runs.
+    estimated_savings: u64,
+    /// Physical bytes freed, in the groups where it was measured.
+    bytes_freed: u64,
+    /// Bytes of zeros punched out of files by `sparsify`, or that would be in a dry run.
+    bytes_punched: u64,
+    /// Bytes given storage of their own by `unshare`, or that would be in a dry run.
+    bytes_unshared: u64,
+    any_failed: bool,
+    /// Whether `unshare` did nothing, as there wasn't enough free space for it.
+    not_enough_space: bool,
+    /// Targets that were never started, because the run was stopped early.
+    targets_remaining: u64,
+    /// Whether targets were left unread, so aren't counted in [targets_remaining].
+    input_unread: bool,
+    /// The budget that stopped the run early, if any.
+    budget_exhausted: Option<&'static str>,
+    report: Option<Report>,
+    /// Groups that had something to de-dupe.
+    groups_ok: u64,
+    /// Groups that had nothing to de-dupe.
+    groups_skipped: u64,
+    groups_failed: u64,
+    /// Groups that were de-duped, but failed for some of their destinations.
+    groups_partially_failed: u64,
+    /// Errors for whole groups or single destinations, keyed by error kind and errno.
+    errors: BTreeMap<(String, Option<i32>), u64>,
+    /// Every group with a failure, to list at the end of the run.
+    failures: Vec<Failure>,
+    /// Savings by directory, if asked for.
+    by_dir: Option<DirSummary>,
+}
+
+/// A group that failed, or that failed for some of its destinations.
+struct Failure {
+    /// The group's files if it failed as a whole, otherwise just its source section.
+    files: Vec<PathBuf>,
+    /// The range of the source that was being de-duped, if it got that far.
+    range: Option<Range<u64>>,
+    /// Why the whole group failed.
+    error: Option<String>,
+    /// Destinations that failed, and why.
+    destinations: Vec<(FileOffset, String)>,
+}
+
+impl Tracker {
+    fn record_result(&mut self, result: DedupeResult) {
+        if let Some(report) = &mut self.report {
+            if let Err(e) = report.record_result(&result) {
+                error!(errno = e.raw_os_error(); "Failed to write report, disabling it: {}", e);
+                self.report = None;
+            }
+        }
+        match result {
+            Ok(Some(ref dedupe)) => {
+                self.groups_ok += 1;
+                self.max_bytes_saved += dedupe.total_bytes_saved;
+                self.bytes_freed += dedupe.bytes_freed().unwrap_or(0);
+                if let Some(by_dir) = &mut self.by_dir {
+                    let freed = dedupe.bytes_freed().unwrap_or(0);
+                    for (section, bytes_deduped) in &dedupe.bytes_deduped_by_offset {
+                        let totals = by_dir.totals_for(section.file());
+                        totals.bytes_deduped += bytes_deduped;
+                        // Share what was freed by how much of each destination was de-duped.
+                        totals.bytes_freed += (freed as u128 * *bytes_deduped as u128
+                            / dedupe.total_bytes_saved as u128)
+                            as u64;
+                    }
+                }
+                for error in dedupe.offsets_errored.values() {
+                    self.record_error(error);
+                }
+                if !dedupe.offsets_errored.is_empty() {
+                    self.any_failed = true;
+                    self.groups_partially_failed += 1;
+                    let source = &dedupe.offset_targeted;
+                    let mut destinations = dedupe
+                        .offsets_errored
+                        .iter()
+                        .map(|(section, error)| (section.clone(), error.to_string()))
+                        .collect::<Vec<_>>();
+                    destinations.sort_by(|(a, _), (b, _)| a.file().cmp(b.file()));
+                    self.failures.push(Failure {
+                        files: vec![source.file().to_path_buf()],
+                        range: Some(source.offset()..(source.offset() + dedupe.size)),
+                        error: None,
+                        destinations,
+                    });
+                }
+            }
+            Ok(_) => self.groups_skipped += 1,
+            Err(ref e) => self.record_failure(e),
+        };
+        print_task_completion(result);
+    }
+
+    fn record_plan(&mut self, result: PlanResult) {
+        if let Some(report) = &mut self.report {
+            if let Err(e) = report.record_plan(&result) {
+                error!(errno = e.raw_os_error(); "Failed to write report, disabling it: {}", e);
+                self.report = None;
+            }
+        }
+        match result {
+            Ok(Some(ref plan)) => {
+                self.groups_ok += 1;
+                self.estimated_savings += plan.estimated_savings;
+                if let Some(by_dir) = &mut self.by_dir {
+                    // Share the estimate evenly between the destinations.
+                    let dests = &plan.target.offsets[1..];
+                    for (i, section) in dests.iter().enumerate() {
+                        let share = plan.estimated_savings / dests.len() as u64;
+                        let remainder = plan.estimated_savings % dests.len() as u64;
+                        by_dir.totals_for(section.file()).estimated_savings +=
+                            share + if i == 0 { remainder } else { 0 };
+                    }
+                }
+            }
+            Ok(_) => self.groups_skipped += 1,
+            Err(ref e) => self.record_failure(e),
+        };
+        print_plan(result);
+    }
+
+    fn record_sparsify(&mut self, result: SparsifyResult) {
+        if let Some(report) = &mut self.report {
+            if let Err(e) = report.record_sparsify(&result) {
+                error!(errno = e.raw_os_error(); "Failed to write report, disabling it: {}", e);
+                self.report = None;
+            }
+        }
+        match result {
+            Ok(Some(ref info)) => {
+                self.groups_ok += 1;
+                self.bytes_punched += info.bytes_zero;
+                match info.bytes_freed {
+                    Some(freed) => self.bytes_freed += freed,
+                    None => self.estimated_savings += info.bytes_zero,
+                }
+                if let Some(by_dir) = &mut self.by_dir {
+                    let totals = by_dir.totals_for(&info.path);
+                    match info.bytes_freed {
+                        Some(freed) => {
+                            totals.bytes_punched += info.bytes_zero;
+                            totals.bytes_freed += freed;
+                        }
+                        None => totals.estimated_savings += info.bytes_zero,
+                    }
+                }
+            }
+            Ok(_) => self.groups_skipped += 1,
+            Err(ref e) => self.record_failure(e),
+        };
+        print_sparsify(result);
+    }
+
+    fn record_unshare(&mut self, result: UnshareResult) {
+        if let Some(report) = &mut self.report {
+            if let Err(e) = report.record_unshare(&result) {
+                error!(errno = e.raw_os_error(); "Failed to write report, disabling it: {}", e);
+                self.report = None;
+            }
+        }
+        match result {
+            Ok(Some(ref info)) => {
+                self.groups_ok += 1;
+                let unshared = info.bytes_unshared.unwrap_or(info.bytes_shared);
+                self.bytes_unshared += unshared;
+                if let Some(by_dir) = &mut self.by_dir {
+                    by_dir.totals_for(&info.path).bytes_unshared += unshared;
+                }
+            }
+            Ok(_) => self.groups_skipped += 1,
+            Err(ref e) => self.record_failure(e),
+        };
+        print_unshare(result);
+    }
+
+    fn record_failure(&mut self, e: &DedupeError) {
+        self.any_failed = true;
+        self.groups_failed += 1;
+        self.record_error(&e.source);
+        self.failures.push(Failure {
+            files: e.target.files().to_vec(),
+            range: None,
+            error: Some(e.source.to_string()),
+            destinations: Vec::new(),
+        });
+    }
+
+    /// Count [targets] and the one that was just taken from it as remaining. Targets whose number
+    /// isn't already known, like those read from stdin, are left unread rather than waiting for
+    /// the rest of the input.
+    fn count_remaining(&mut self, targets: Box<dyn Iterator<Item = DeduplicationTarget>>) {
+        self.targets_remaining += 1;
+        match targets.size_hint() {
+            (lower, Some(upper)) if lower == upper => self.targets_remaining += upper as u64,
+            _ => self.input_unread = true,
+        }
+    }
+
+    /// The code to exit with, if the run wasn't a complete success.
+    fn exit_code(&self) -> Option<i32> {
+        if self.not_enough_space {
+            return Some(EXIT_NO_SPACE);
+        }
+        if !self.any_failed {
+            return None;
+        }
+        let processed = self.groups_ok + self.groups_skipped + self.groups_failed;
+        // A group that only failed for some destinations still saved space.
+        if self.groups_failed < processed || self.groups_partially_failed > 0 {
+            return Some(EXIT_PARTIAL_FAILURE);
+        }
+        let unsupported = self
+            .errors
+            .keys()
+            .all(|(_, errno)| errno.is_some_and(|errno| UNSUPPORTED_ERRNOS.contains(&errno)));
+        if unsupported {
+            Some(EXIT_UNSUPPORTED)
+        } else {
+            Some(EXIT_TOTAL_FAILURE)
+        }
+    }
+
+    fn record_error(&mut self, error: &std::io::Error) {
+        *self
+            .errors
+            .entry((format!("{:?}", error.kind()), error.raw_os_error()))
+            .or_default() += 1;
+    }
+}
+
+/// List every failure together, so they don't have to be found among the successful groups.
+fn print_failures(failures: &[Failure]) {
+    if failures.is_empty() {
+        return;
+    }
+    let mut message = format!("{} groups had failures:", HumanCount(failures.len() as u64));
+    for failure in failures {
+        let files = failure
+            .files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match &failure.range {
+            Some(range) => message += &format!("\n    {} [{}-{}]", files, range.start, range.end),
+            None => message += &format!("\n    {}", files),
+        }
+        if let Some(error) = &failure.error {
+            message += &format!(": {}", error);
+        }
+        for (section, error) in &failure.destinations {
+            message += &format!(
+                "\n        -> {} [{}]: {}",
+                section.file().display(),
+                section.offset(),
+                error
+            );
+        }
+    }
+    error!(target: SUMMARY, failures = failures.len(); "{}", message);
+}
+
+fn print_plan(result: PlanResult) {
+    match result {
+        Ok(Some(plan)) => {
+            let (source, dests) = plan.target.offsets.split_first().unwrap();
+            let mut message = format!(
+                "==> Would de-dupe from {} [{}-{}], estimated to free {}",
+                source.file().display(),
+                source.offset(),
+                source.offset() + plan.target.length,
+                HumanBytes(plan.estimated_savings),
+            );
+            for dest in dests {
+                message += &format!("\n    -> {} [{}]", dest.file().display(), dest.offset())
+                    .success_style()
+                    .to_string();
+            }
+            let already_shared = plan.all_sections.offsets.len() - plan.target.offsets.len();
+            if already_shared > 0 {
+                message += &format!("\n    ({} already shared)", already_shared);
+            }
+            info!(
+                path:% = source.file().display(), offset = source.offset(), len = plan.target.length,
+                dests = dests.len(), already_shared, estimated_savings = plan.estimated_savings;
+                "{}", message
+            );
+        }
+        Ok(_) => {}
+        Err(e) => print_dedupe_error(e),
+    }
+}
+
+fn print_task_completion(result: DedupeResult) {
+    match result {
+        Ok(Some(dedupe)) => {
+            let source = &dedupe.offset_targeted;
+            let mut message = format!(
+                "==> De-dupe Targeting {} [{}-{}]",
+                source.file().display(),
+                source.offset(),
+                source.offset() + dedupe.size,
+            );
+            if !dedupe.offsets_affected.is_empty() {
+                message += &match dedupe.bytes_freed() {
+                    Some(freed) => format!(
+                        "\nSaved {} (freed {}) by re-using content in:",
+                        HumanBytes(dedupe.total_bytes_saved),
+                        HumanBytes(freed),
+                    ),
+                    None => format!(
+                        "\nSaved {} by re-using content in:",
+                        HumanBytes(dedupe.total_bytes_saved),
+                    ),
+                };
+                for affected in &dedupe.offsets_affected {
+                    message += &format!("\n    {}", affected.file().display());
+                }
+            }
+            info!(
+                path:% = source.file().display(), offset = source.offset(), len = dedupe.size,
+                affected = dedupe.offsets_affected.len(), errored = dedupe.offsets_errored.len(),
+                bytes_deduped = dedupe.total_bytes_saved, bytes_freed = dedupe.bytes_freed();
+                "{}", message
+            );
+            for (section, error) in &dedupe.offsets_errored {
+                error!(
+                    path:% = section.file().display(), offset = section.offset(),
+                    len = dedupe.size, src:% = source.file().display(),
+                    errno = error.raw_os_error();
+                    "    {}: {}", section.file().display(), error
+                );
+            }
+            for (section, range) in &dedupe.source_holes {
+                info!(
+                    path:% = section.file().display(), offset = range.start,
+                    len = range.end - range.start, src:% = source.file().display();
+                    "    {}: has data at {}-{} where the source has a hole",
+                    section.file().display(), range.start, range.end
+                );
+            }
+        }
+        Ok(_) => {}
+        Err(e) => print_dedupe_error(e),
+    }
+}
+
+fn print_dedupe_error(e: DedupeError) {
+    let files = e.target.files();
+    let mut message = match e.target {
+        DeduplicationTarget::Files(_) | DeduplicationTarget::Planned { .. } => {
+            format!("Got {} while trying to dedupe these files:", e.source)
+        }
+        DeduplicationTarget::Sparsify { .. } => {
+            format!("Got {} while trying to sparsify:", e.source)
+        }
+        DeduplicationTarget::Unshare(_) => format!("Got {} while trying to unshare:", e.source),
+    };
+    for targeted in files {
+        message += &format!("\n    {}", targeted.display());
+    }
+    error!(
+        path:% = files[0].display(), files = files.len(), errno = e.source.raw_os_error();
+        "{}", message
+    );
+}
+
+#[derive(Error, Debug)]
+#[error("Error while de-duplicating {target:?}: {source}")]
+struct DedupeError {
+    target: DeduplicationTarget,
+    source: std::io::Error,
+}
+
+impl DedupeInfo {
+    /// Physical bytes actually freed, if they were measured. Unlike [total_bytes_saved], this
+    /// doesn't count bytes that were already shared, or were only compared by the kernel.
+    fn bytes_freed(&self) -> Option<u64> {
+        Some(self.physical_before?.saturating_sub(self.physical_after?))
+    }
+}
+
+#[derive(Debug)]
+struct DedupeInfo {
+    size: u64,
+    offset_targeted: FileOffset,
+    offsets_errored: HashMap<FileOffset, std::io::Error>,
+    offsets_affected: Vec<FileOffset>,
+    /// Bytes de-duplicated in each affected destination.
+    bytes_deduped_by_offset: HashMap<FileOffset, u64>,
+    total_bytes_saved: u64,
+    /// How many times a destination range was found to differ from the source.
+    differs: u64,
+    /// Ranges of destinations that have data where the source has a hole.
+    source_holes: Vec<(FileOffset, Range<u64>)>,
+    /// Physical bytes used by all sections before de-duplicating, if measured.
+    physical_before: Option<u64>,
+    /// Physical bytes used by all sections after de-duplicating, if measured.
+    physical_after: Option<u64>,
+    duration: Duration,
+    /// How much of [duration] was spent in the de-dupe ioctls.
+    ioctl_duration: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    use dedupetool::diskblade::{FileOffset, FileSectionTarget};
+    use dedupetool::ioctl_fiemap::Extent;
+
+    use super::estimate_savings;
+
+    const LENGTH: u64 = 8192;
+
+    /// Three sections of [LENGTH] bytes. The paths only need to exist.
+    fn target() -> FileSectionTarget {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        FileSectionTarget {
+            length: LENGTH,
+            offsets: ["Cargo.toml", "README.md", "LICENSE.txt"]
+                .into_iter()
+                .map(|name| FileOffset::new(dir.join(name), 0))
+                .collect(),
+        }
+    }
+
+    /// Each section stored in one extent, at the given physical offsets.
+    fn stored_at(physical_offsets: &[u64]) -> Vec<Vec<Extent>> {
+        physical_offsets
+            .iter()
+            .map(|&physical_offset| {
+                vec![Extent {
+                    logical_offset: 0,
+                    physical_offset,
+                    length: LENGTH,
+                    flags: BTreeSet::new(),
+                }]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn savings_without_extents() {
+        assert_eq!(estimate_savings(&target(), None), 2 * LENGTH);
+    }
+
+    #[test]
+    fn savings_of_separate_copies() {
+        let extents = stored_at(&[0, 1 << 20, 2 << 20]);
+        assert_eq!(estimate_savings(&target(), Some(&extents)), 2 * LENGTH);
+    }
+
+    #[test]
+    fn savings_when_already_sharing_the_source() {
+        let extents = stored_at(&[1 << 20, 1 << 20, 1 << 20]);
+        assert_eq!(estimate_savings(&target(), Some(&extents)), 0);
+        // Only the last destination still has its own copy.
+        let extents = stored_at(&[1 << 20, 1 << 20, 2 << 20]);
+        assert_eq!(estimate_savings(&target(), Some(&extents)), LENGTH);
+        // The destinations share with each other, but not with the source.
+        let extents = stored_at(&[0, 1 << 20, 1 << 20]);
+        assert_eq!(estimate_savings(&target(), Some(&extents)), LENGTH);
+    }
+}
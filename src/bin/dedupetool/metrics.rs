@@ -0,0 +1,128 @@
+//! Prometheus metrics, in the text format read by node_exporter's textfile collector.
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::Tracker;
+
+/// Everything the metrics file reports, as of when it is written.
+pub struct Metrics<'a> {
+    pub tracker: &'a Tracker,
+    pub bytes_compared: u64,
+    pub duration: Duration,
+    /// Whether physical bytes freed were measured.
+    pub bytes_freed_measured: bool,
+    pub dry_run: bool,
+}
+
+impl Metrics<'_> {
+    pub fn render(&self) -> String {
+        let tracker = self.tracker;
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "dedupetool_groups_processed_total",
+            "counter",
+            "Groups processed, by result.",
+            &[
+                (r#"result="ok""#.to_string(), tracker.groups_ok as f64),
+                (
+                    r#"result="skipped""#.to_string(),
+                    tracker.groups_skipped as f64,
+                ),
+                (
+                    r#"result="failed""#.to_string(),
+                    tracker.groups_failed as f64,
+                ),
+            ],
+        );
+        metric(
+            &mut out,
+            "dedupetool_bytes_compared_total",
+            "counter",
+            "Bytes the kernel was asked to compare, counted once per destination.",
+            &[(String::new(), self.bytes_compared as f64)],
+        );
+        metric(
+            &mut out,
+            "dedupetool_bytes_deduped_total",
+            "counter",
+            "Bytes the kernel reported as de-duplicated, including ones that were already shared.",
+            &[(String::new(), tracker.max_bytes_saved as f64)],
+        );
+        if self.bytes_freed_measured {
+            metric(
+                &mut out,
+                "dedupetool_bytes_freed_total",
+                "counter",
+                "Physical bytes actually freed.",
+                &[(String::new(), tracker.bytes_freed as f64)],
+            );
+        }
+        if self.dry_run {
+            metric(
+                &mut out,
+                "dedupetool_estimated_savings_bytes",
+                "gauge",
+                "Bytes estimated to be freed by de-duplicating.",
+                &[(String::new(), tracker.estimated_savings as f64)],
+            );
+        }
+        metric(
+            &mut out,
+            "dedupetool_errors_total",
+            "counter",
+            "Errors, for whole groups or single destinations, by error kind and errno.",
+            &tracker
+                .errors
+                .iter()
+                .map(|((kind, errno), count)| {
+                    let errno = errno.map(|e| e.to_string()).unwrap_or_default();
+                    (
+                        format!(r#"kind="{}",errno="{}""#, kind, errno),
+                        *count as f64,
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            &mut out,
+            "dedupetool_run_duration_seconds",
+            "gauge",
+            "How long the run has been going.",
+            &[(String::new(), self.duration.as_secs_f64())],
+        );
+        out
+    }
+}
+
+/// Write rendered metrics to [path], atomically so the collector never sees a partial file.
+pub async fn write(path: &Path, metrics: String) -> Result<(), std::io::Error> {
+    let temp_path = temp_path(path);
+    tokio::fs::write(&temp_path, metrics).await?;
+    tokio::fs::rename(&temp_path, path).await
+}
+
+/// Append a metric with its help and type lines. Each sample is its labels and value.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+/// A path next to [path] to write to before renaming. The collector only reads `*.prom` files,
+/// so it won't pick this up.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
@@ -0,0 +1,137 @@
+//! Live progress display on stderr. Hidden automatically when stderr isn't a terminal.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::Instant;
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget};
+
+use dedupetool::termhelp::{log_diag, DedupetoolProgressBar};
+
+/// A spinner while targets are discovered, then a bar of targets processed, and one of bytes
+/// compared once there are any to compare.
+pub struct Progress {
+    multi: MultiProgress,
+    bar: ProgressBar,
+    bytes: ProgressBar,
+    /// Adds [bytes] to the display the first time there are bytes to compare.
+    bytes_shown: Once,
+    /// Whether the bar's length is the real number of targets, or grows as they're found.
+    length_known: AtomicBool,
+    /// Whether the bytes bar's length is the real number of bytes to compare, or grows as
+    /// targets are planned.
+    bytes_known: AtomicBool,
+    /// When processing started, for working out throughput.
+    started: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let bar = ProgressBar::dedupetool_spinner("targets");
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+        bar.set_message("Discovering");
+        let bar = multi.add(bar).with_steady_tick_dedupetool();
+        // Neither is drawn until it's added to [multi].
+        let bytes = ProgressBar::dedupetool_bytes_bar();
+        bytes.set_draw_target(ProgressDrawTarget::hidden());
+        Progress {
+            multi,
+            bar,
+            bytes,
+            bytes_shown: Once::new(),
+            length_known: AtomicBool::new(false),
+            bytes_known: AtomicBool::new(false),
+            started: Mutex::new(None),
+        }
+    }
+
+    /// Update the discovery spinner's message, and start counting again.
+    pub fn set_discovery_message(&self, message: &'static str) {
+        self.bar.set_position(0);
+        self.bar.set_message(message);
+    }
+
+    /// Count a target, or a file that may become one, on the discovery spinner.
+    pub fn target_discovered(&self) {
+        self.bar.inc(1);
+    }
+
+    /// Switch from the discovery spinner to the bar. [length] is the number of targets, and
+    /// [bytes] the number of bytes they'll compare, if known.
+    pub fn start_processing(&self, length: Option<u64>, bytes: Option<u64>) {
+        self.length_known.store(length.is_some(), Ordering::Relaxed);
+        self.bar.set_position(0);
+        self.bar.set_length(length.unwrap_or(0));
+        self.bar.set_style_dedupetool();
+        self.bar.set_message("");
+        self.bytes_known.store(bytes.is_some(), Ordering::Relaxed);
+        if let Some(bytes) = bytes {
+            self.bytes.set_length(bytes);
+            self.show_bytes();
+        }
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Count a target that was found while processing, when the total wasn't known up front.
+    pub fn target_found(&self) {
+        if !self.length_known.load(Ordering::Relaxed) {
+            self.bar.inc_length(1);
+        }
+    }
+
+    /// Count the bytes a target will compare, once it's been planned, when the total wasn't
+    /// known up front.
+    pub fn bytes_found(&self, bytes: u64) {
+        if !self.bytes_known.load(Ordering::Relaxed) {
+            self.bytes.inc_length(bytes);
+        }
+        self.show_bytes();
+    }
+
+    pub fn target_done(&self) {
+        self.bar.inc(1);
+    }
+
+    fn show_bytes(&self) {
+        self.bytes_shown.call_once(|| {
+            self.multi.add(self.bytes.clone());
+        });
+    }
+
+    /// Update the bytes bar, and the throughput shown next to it.
+    pub fn update_bytes(&self, bytes_compared: u64) {
+        let Some(started) = *self.started.lock().unwrap() else {
+            return;
+        };
+        self.bytes.set_position(bytes_compared);
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (bytes_compared as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let mut message = format!("{}/s", HumanBytes(rate));
+        if self.bytes_known.load(Ordering::Relaxed) {
+            message += &format!(", ETA {}", HumanDuration(self.bytes.eta()));
+        } else if self.length_known.load(Ordering::Relaxed) {
+            message += &format!(", ETA {}", HumanDuration(self.bar.eta()));
+        }
+        self.bytes.set_message(message);
+    }
+
+    /// Print a line to stderr without tearing the bars.
+    pub fn println<D: Display>(&self, msg: D) {
+        if self.multi.is_hidden() {
+            log_diag(msg);
+        } else {
+            let _ = self.multi.println(msg.to_string());
+        }
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+        self.bytes.finish_and_clear();
+    }
+}
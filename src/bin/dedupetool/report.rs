@@ -0,0 +1,354 @@
+//! Machine-readable reports of a run, for dashboards and other tooling.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use dedupetool::diskblade::{serialize_path_lossy, FileOffset};
+
+use crate::dir_summary::DirRecord;
+use crate::sparsify::SparsifyResult;
+use crate::unshare::UnshareResult;
+use crate::{DedupeError, DedupeResult, PlanResult};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    /// A single JSON document, with a `groups` array and a `summary` object.
+    Json,
+    /// One JSON record per line, written as the run progresses.
+    Ndjson,
+}
+
+/// Writes the report for a run.
+///
+/// Records are serialized by the caller, but written out on a thread of their own, so a slow disk
+/// doesn't hold up the runtime.
+pub struct Report {
+    format: ReportFormat,
+    sender: Sender<Vec<u8>>,
+    /// Writes what's sent to it, until it's dropped or a write fails.
+    writer: Option<JoinHandle<Result<(), std::io::Error>>>,
+    /// Whether any group records have been written yet.
+    any_groups: bool,
+}
+
+impl Report {
+    pub fn create(path: &Path, format: ReportFormat) -> Result<Report, std::io::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let (sender, receiver) = channel::<Vec<u8>>();
+        let writer = std::thread::Builder::new()
+            .name("report".to_string())
+            .spawn(move || {
+                for bytes in receiver {
+                    file.write_all(&bytes)?;
+                    // Flush every NDJSON record, so that the report can be followed while the
+                    // run is going.
+                    if let ReportFormat::Ndjson = format {
+                        file.flush()?;
+                    }
+                }
+                file.flush()
+            })?;
+        let mut report = Report {
+            format,
+            sender,
+            writer: Some(writer),
+            any_groups: false,
+        };
+        if let ReportFormat::Json = format {
+            report.send(b"{\"groups\":[".to_vec())?;
+        }
+        Ok(report)
+    }
+
+    pub fn record_result(&mut self, result: &DedupeResult) -> Result<(), std::io::Error> {
+        let record = match result {
+            Ok(Some(dedupe)) => Record::Group(GroupRecord {
+                source: &dedupe.offset_targeted,
+                length: dedupe.size,
+                affected: &dedupe.offsets_affected,
+                bytes_deduped: dedupe.total_bytes_saved,
+                bytes_freed: dedupe.bytes_freed(),
+                physical_bytes_before: dedupe.physical_before,
+                physical_bytes_after: dedupe.physical_after,
+                errors: dedupe
+                    .offsets_errored
+                    .iter()
+                    .map(|(section, error)| DestinationError {
+                        destination: section,
+                        error: ErrorRecord::from(error),
+                    })
+                    .collect(),
+                differs: dedupe.differs,
+                duration_secs: dedupe.duration.as_secs_f64(),
+                ioctl_duration_secs: dedupe.ioctl_duration.as_secs_f64(),
+            }),
+            Ok(None) => return Ok(()),
+            Err(e) => Record::GroupError(GroupErrorRecord::from(e)),
+        };
+        self.write_group(&record)
+    }
+
+    pub fn record_plan(&mut self, result: &PlanResult) -> Result<(), std::io::Error> {
+        let record = match result {
+            Ok(Some(plan)) => Record::Planned(PlannedRecord {
+                source: &plan.target.offsets[0],
+                length: plan.target.length,
+                affected: &plan.target.offsets[1..],
+                already_shared: plan.all_sections.offsets.len() - plan.target.offsets.len(),
+                estimated_savings: plan.estimated_savings,
+                physical_bytes_before: plan.physical_before,
+            }),
+            Ok(None) => return Ok(()),
+            Err(e) => Record::GroupError(GroupErrorRecord::from(e)),
+        };
+        self.write_group(&record)
+    }
+
+    pub fn record_sparsify(&mut self, result: &SparsifyResult) -> Result<(), std::io::Error> {
+        let record = match result {
+            Ok(Some(info)) => Record::Sparsified(SparsifiedRecord {
+                path: &info.path,
+                size: info.size,
+                zero_ranges: &info.zero_ranges,
+                bytes_punched: info.bytes_zero,
+                bytes_freed: info.bytes_freed,
+                duration_secs: info.duration.as_secs_f64(),
+            }),
+            Ok(None) => return Ok(()),
+            Err(e) => Record::GroupError(GroupErrorRecord::from(e)),
+        };
+        self.write_group(&record)
+    }
+
+    pub fn record_unshare(&mut self, result: &UnshareResult) -> Result<(), std::io::Error> {
+        let record = match result {
+            Ok(Some(info)) => Record::Unshared(UnsharedRecord {
+                path: &info.path,
+                size: info.size,
+                shared_ranges: &info.shared_ranges,
+                bytes_unshared: info.bytes_unshared,
+                copied: info.copied,
+                duration_secs: info.duration.as_secs_f64(),
+            }),
+            Ok(None) => return Ok(()),
+            Err(e) => Record::GroupError(GroupErrorRecord::from(e)),
+        };
+        self.write_group(&record)
+    }
+
+    fn write_group(&mut self, record: &Record) -> Result<(), std::io::Error> {
+        let mut bytes = Vec::new();
+        match self.format {
+            ReportFormat::Json => {
+                if self.any_groups {
+                    bytes.push(b',');
+                }
+                self.any_groups = true;
+                serde_json::to_writer(&mut bytes, record)?;
+            }
+            ReportFormat::Ndjson => {
+                serde_json::to_writer(&mut bytes, record)?;
+                bytes.push(b'\n');
+            }
+        }
+        self.send(bytes)
+    }
+
+    /// Write the summary, and anything else that is left, to the report, and wait for it all to
+    /// be written.
+    pub async fn finish(mut self, summary: SummaryRecord<'_>) -> Result<(), std::io::Error> {
+        let mut bytes = Vec::new();
+        match self.format {
+            ReportFormat::Json => {
+                bytes.extend_from_slice(b"],\"summary\":");
+                serde_json::to_writer(&mut bytes, &summary)?;
+                bytes.extend_from_slice(b"}\n");
+            }
+            ReportFormat::Ndjson => {
+                serde_json::to_writer(&mut bytes, &Record::Summary(summary))?;
+                bytes.push(b'\n');
+            }
+        }
+        self.send(bytes)?;
+        let Report { sender, writer, .. } = self;
+        drop(sender);
+        match writer {
+            Some(writer) => tokio::task::spawn_blocking(move || join(writer))
+                .await
+                .expect("failed to spawn blocking"),
+            None => Ok(()),
+        }
+    }
+
+    /// Hand [bytes] to the writer thread. If it has stopped, returns the error that stopped it.
+    fn send(&mut self, bytes: Vec<u8>) -> Result<(), std::io::Error> {
+        if self.sender.send(bytes).is_ok() {
+            return Ok(());
+        }
+        // The receiver is only dropped when the thread is finishing, so this won't wait long.
+        if let Some(writer) = self.writer.take() {
+            join(writer)?;
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "Report writer stopped",
+        ))
+    }
+}
+
+fn join(writer: JoinHandle<Result<(), std::io::Error>>) -> Result<(), std::io::Error> {
+    writer.join().expect("report writer panicked")
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Group(GroupRecord<'a>),
+    Planned(PlannedRecord<'a>),
+    GroupError(GroupErrorRecord<'a>),
+    Sparsified(SparsifiedRecord<'a>),
+    Unshared(UnsharedRecord<'a>),
+    Summary(SummaryRecord<'a>),
+}
+
+/// A group that was de-duplicated, possibly with errors for some destinations.
+#[derive(Serialize)]
+struct GroupRecord<'a> {
+    source: &'a FileOffset,
+    length: u64,
+    affected: &'a [FileOffset],
+    /// Bytes the kernel reported as de-duplicated, including ones that were already shared.
+    bytes_deduped: u64,
+    /// Physical bytes actually freed, if measured.
+    bytes_freed: Option<u64>,
+    physical_bytes_before: Option<u64>,
+    physical_bytes_after: Option<u64>,
+    errors: Vec<DestinationError<'a>>,
+    /// How many times the kernel reported that a destination range differed from the source.
+    differs: u64,
+    duration_secs: f64,
+    ioctl_duration_secs: f64,
+}
+
+/// A group that would be de-duplicated, for dry runs.
+#[derive(Serialize)]
+struct PlannedRecord<'a> {
+    source: &'a FileOffset,
+    length: u64,
+    affected: &'a [FileOffset],
+    /// Destinations left out because they already share the source's storage.
+    already_shared: usize,
+    estimated_savings: u64,
+    physical_bytes_before: Option<u64>,
+}
+
+/// A file that had runs of zeros punched out of it, or would have in a dry run.
+#[derive(Serialize)]
+struct SparsifiedRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    size: u64,
+    zero_ranges: &'a [Range<u64>],
+    bytes_punched: u64,
+    /// Allocated bytes freed, or `None` for a dry run.
+    bytes_freed: Option<u64>,
+    duration_secs: f64,
+}
+
+/// A file that had its shared extents given storage of their own, or would have in a dry run.
+#[derive(Serialize)]
+struct UnsharedRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    size: u64,
+    shared_ranges: &'a [Range<u64>],
+    /// Bytes unshared, or `None` for a dry run.
+    bytes_unshared: Option<u64>,
+    /// Whether the file was replaced with a copy, as it couldn't be unshared in place.
+    copied: bool,
+    duration_secs: f64,
+}
+
+#[derive(Serialize)]
+struct DestinationError<'a> {
+    destination: &'a FileOffset,
+    error: ErrorRecord,
+}
+
+/// A group that couldn't be de-duplicated at all.
+#[derive(Serialize)]
+struct GroupErrorRecord<'a> {
+    files: Vec<Cow<'a, str>>,
+    error: ErrorRecord,
+}
+
+impl<'a> From<&'a DedupeError> for GroupErrorRecord<'a> {
+    fn from(error: &'a DedupeError) -> Self {
+        GroupErrorRecord {
+            files: error
+                .target
+                .files()
+                .iter()
+                .map(|f| f.to_string_lossy())
+                .collect(),
+            error: ErrorRecord::from(&error.source),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorRecord {
+    kind: String,
+    errno: Option<i32>,
+    message: String,
+}
+
+impl From<&std::io::Error> for ErrorRecord {
+    fn from(error: &std::io::Error) -> Self {
+        ErrorRecord {
+            kind: format!("{:?}", error.kind()),
+            errno: error.raw_os_error(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// The totals for the whole run.
+#[derive(Serialize)]
+pub struct SummaryRecord<'a> {
+    pub bytes_deduped: u64,
+    /// Physical bytes actually freed, if measured.
+    pub bytes_freed: Option<u64>,
+    /// Bytes estimated to be freed, for dry runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_savings: Option<u64>,
+    /// Bytes of zeros punched out, for `sparsify` runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_punched: Option<u64>,
+    /// Bytes given storage of their own, for `unshare` runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_unshared: Option<u64>,
+    pub any_failed: bool,
+    /// Whether nothing was unshared as there wasn't enough free space, for `unshare` runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_enough_space: Option<bool>,
+    pub interrupted: bool,
+    /// The budget that ran out, if the run was stopped because of one.
+    pub budget_exhausted: Option<&'static str>,
+    pub targets_remaining: u64,
+    /// Whether the run stopped before reading all of its input, so more targets remain than
+    /// [targets_remaining] counts.
+    pub input_unread: bool,
+    pub duration_secs: f64,
+    /// Savings by directory, biggest first, if asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_dir: Option<Vec<DirRecord<'a>>>,
+}
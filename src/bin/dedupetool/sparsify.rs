@@ -0,0 +1,223 @@
+//! `sparsify`: punching out runs of zeros that take up space, rather than de-duping.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use indicatif::HumanBytes;
+use log::{debug, info};
+
+use dedupetool::ioctl_fiemap::{get_extents, ExtentFlag, MapFlags};
+use dedupetool::sparse::{merge_ranges, punch_hole, zero_ranges};
+
+use crate::{print_dedupe_error, DedupeContext, DedupeError, DeduplicationTarget};
+
+pub type SparsifyResult = Result<Option<SparsifyInfo>, DedupeError>;
+
+/// What was found in a file, and what was done about it.
+#[derive(Debug)]
+pub struct SparsifyInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Runs of all-zero blocks. Punched out, unless it's a dry run.
+    pub zero_ranges: Vec<Range<u64>>,
+    /// Bytes in [zero_ranges].
+    pub bytes_zero: u64,
+    /// Allocated bytes freed, as measured by the file's block count. `None` for dry runs.
+    pub bytes_freed: Option<u64>,
+    pub duration: Duration,
+}
+
+/// The runs of zeros found in a file, and what the file looked like when they were found.
+#[derive(Debug, Clone)]
+pub struct ZeroScan {
+    size: u64,
+    mtime: (i64, i64),
+    zero_ranges: Vec<Range<u64>>,
+    /// Bytes read looking for zeros.
+    pub bytes_read: u64,
+}
+
+impl ZeroScan {
+    pub fn bytes_zero(&self) -> u64 {
+        self.zero_ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Whether the file still looks like it did when it was scanned.
+    fn is_current(&self, metadata: &Metadata) -> bool {
+        (self.size, self.mtime) == (metadata.size(), (metadata.mtime(), metadata.mtime_nsec()))
+    }
+}
+
+pub async fn process_sparsify(
+    ctx: &DedupeContext,
+    path: PathBuf,
+    scan: Option<Box<ZeroScan>>,
+) -> SparsifyResult {
+    let result = tokio::task::spawn_blocking({
+        let ctx = ctx.clone();
+        let path = path.clone();
+        move || sparsify_file(&ctx, &path, scan.map(|scan| *scan))
+    })
+    .await
+    .expect("failed to spawn blocking");
+    result.map_err(|e| DedupeError {
+        target: DeduplicationTarget::Sparsify { path, scan: None },
+        source: e,
+    })
+}
+
+/// Find the runs of zeros in [path] for `--prioritize`, so they can be punched out later without
+/// reading it again. The bytes read are throttled, but only counted against the budget if the
+/// file is processed.
+pub async fn scan_sparsify(ctx: &DedupeContext, path: &Path) -> Result<ZeroScan, std::io::Error> {
+    let ctx = ctx.clone();
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        scan_zeros(&ctx, &file, &metadata, false)
+    })
+    .await
+    .expect("failed to spawn blocking")
+}
+
+/// Find the runs of zeros in [path], and punch them out unless it's a dry run. [scan] is used
+/// instead of reading the file again if the file hasn't changed since. Returns `None` if there
+/// aren't enough zeros to be worth it.
+fn sparsify_file(
+    ctx: &DedupeContext,
+    path: &Path,
+    scan: Option<ZeroScan>,
+) -> Result<Option<SparsifyInfo>, std::io::Error> {
+    let start = Instant::now();
+    let punch = !ctx.dry_run;
+    let file = OpenOptions::new().read(true).write(punch).open(path)?;
+    let metadata = file.metadata()?;
+    let scan = match scan {
+        Some(scan) if scan.is_current(&metadata) => {
+            ctx.progress.bytes_found(scan.bytes_read);
+            ctx.budget
+                .bytes_compared
+                .fetch_add(scan.bytes_read, Ordering::Relaxed);
+            scan
+        }
+        _ => scan_zeros(ctx, &file, &metadata, true)?,
+    };
+    let bytes_zero = scan.bytes_zero();
+    if bytes_zero == 0 || bytes_zero < ctx.min_savings {
+        return Ok(None);
+    }
+
+    let bytes_freed = if punch {
+        // Anything written since the scan may not be zeros any more, so leave it alone.
+        let current = file.metadata()?;
+        if !scan.is_current(&current) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "File changed while looking for zeros",
+            ));
+        }
+        for range in &scan.zero_ranges {
+            debug!(
+                path:% = path.display(), offset = range.start, len = range.end - range.start;
+                "Punching out zeros"
+            );
+            punch_hole(&file, range.clone())?;
+        }
+        let blocks_after = file.metadata()?.blocks();
+        Some(current.blocks().saturating_sub(blocks_after) * 512)
+    } else {
+        None
+    };
+
+    Ok(Some(SparsifyInfo {
+        path: path.to_path_buf(),
+        size: scan.size,
+        zero_ranges: scan.zero_ranges,
+        bytes_zero,
+        bytes_freed,
+        duration: start.elapsed(),
+    }))
+}
+
+/// Find the runs of all-zero blocks in [file]. The reads are throttled, and counted against the
+/// budget if [charge_budget] is set.
+fn scan_zeros(
+    ctx: &DedupeContext,
+    file: &File,
+    metadata: &Metadata,
+    charge_budget: bool,
+) -> Result<ZeroScan, std::io::Error> {
+    let size = metadata.size();
+    let block_size = metadata.blksize();
+    // Sync first, so data that's still being written out has extents to look at.
+    let extents = get_extents(file, 0..size, MapFlags::SYNC)?;
+    // Holes and unwritten extents already read as zeros without taking up space, and inline
+    // data doesn't have blocks of its own to free.
+    let candidates = merge_ranges(
+        extents
+            .iter()
+            .filter(|e| {
+                !e.flags.contains(&ExtentFlag::Unwritten)
+                    && !e.flags.contains(&ExtentFlag::DataInline)
+            })
+            .filter_map(|e| e.clip(0..size))
+            .map(|e| e.logical_offset..(e.logical_offset + e.length)),
+    );
+
+    if charge_budget {
+        ctx.progress
+            .bytes_found(candidates.iter().map(|r| r.end - r.start).sum());
+    }
+    let dev = metadata.dev();
+    let mut zeros = Vec::new();
+    let mut bytes_read = 0;
+    for range in candidates {
+        zeros.extend(zero_ranges(file, range, block_size, |len| {
+            // Stop part way through when interrupted, and only punch what was found so far.
+            if ctx.is_interrupted() {
+                return false;
+            }
+            if !ctx.throttle.charge(&[(dev, len)], || ctx.is_interrupted()) {
+                return false;
+            }
+            bytes_read += len;
+            if charge_budget {
+                ctx.budget.bytes_compared.fetch_add(len, Ordering::Relaxed);
+            }
+            true
+        })?);
+    }
+    Ok(ZeroScan {
+        size,
+        mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        zero_ranges: merge_ranges(zeros),
+        bytes_read,
+    })
+}
+
+pub fn print_sparsify(result: SparsifyResult) {
+    match result {
+        Ok(Some(info)) => match info.bytes_freed {
+            Some(freed) => info!(
+                path:% = info.path.display(), len = info.size, ranges = info.zero_ranges.len(),
+                bytes_punched = info.bytes_zero, bytes_freed = freed;
+                "==> Punched out {} of zeros (freed {}) from {}",
+                HumanBytes(info.bytes_zero), HumanBytes(freed), info.path.display()
+            ),
+            None => info!(
+                path:% = info.path.display(), len = info.size, ranges = info.zero_ranges.len(),
+                estimated_savings = info.bytes_zero;
+                "==> Would punch out {} of zeros from {}",
+                HumanBytes(info.bytes_zero), info.path.display()
+            ),
+        },
+        Ok(None) => {}
+        Err(e) => print_dedupe_error(e),
+    }
+}
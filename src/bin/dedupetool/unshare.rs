@@ -0,0 +1,338 @@
+//! `unshare`: giving files storage of their own, undoing de-duplication.
+
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use indicatif::HumanBytes;
+use log::{debug, error, info, warn};
+use thiserror::Error;
+
+use dedupetool::device::filesystem_id;
+use dedupetool::ioctl_fiemap::{get_extents, ExtentFlag, MapFlags};
+use dedupetool::sparse::merge_ranges;
+use dedupetool::unshare::{available_space, copy_and_replace, unshare_range, unshares_in_place};
+
+use crate::{print_dedupe_error, regular_files, DedupeContext, DedupeError, DeduplicationTarget};
+
+pub type UnshareResult = Result<Option<UnshareInfo>, DedupeError>;
+
+/// What was shared in a file, and what was done about it.
+#[derive(Debug)]
+pub struct UnshareInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The parts of the file that were shared.
+    pub shared_ranges: Vec<Range<u64>>,
+    /// Bytes in [shared_ranges].
+    pub bytes_shared: u64,
+    /// Bytes given storage of their own. `None` for dry runs.
+    pub bytes_unshared: Option<u64>,
+    /// Whether the filesystem couldn't unshare in place, so the file was replaced with a copy.
+    pub copied: bool,
+    pub duration: Duration,
+}
+
+/// There isn't enough free space on some filesystem to unshare the files asked for.
+#[derive(Debug, Error)]
+#[error("Not enough free space to unshare the files asked for")]
+pub struct NotEnoughSpace;
+
+/// A filesystem's share of the space needed to unshare everything.
+struct FilesystemSpace {
+    /// A file on the filesystem, to name it by.
+    example: PathBuf,
+    /// Whether the filesystem can unshare in place, rather than by copying whole files.
+    in_place: bool,
+    needed: u64,
+    available: u64,
+}
+
+/// Every regular file under [paths] with shared extents, in name order.
+///
+/// Unsharing needs free space, so that's worked out up front for each filesystem: as much as is
+/// shared where files can be unshared in place, otherwise as much as the files take up, as
+/// they're copied. If any filesystem doesn't have enough, this fails, unless it's a dry run.
+pub async fn unshare_targets(
+    ctx: &DedupeContext,
+    paths: Vec<PathBuf>,
+) -> Result<Vec<DeduplicationTarget>, NotEnoughSpace> {
+    let ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || find_unshare_targets(&ctx, &paths))
+        .await
+        .expect("failed to spawn blocking")
+}
+
+fn find_unshare_targets(
+    ctx: &DedupeContext,
+    paths: &[PathBuf],
+) -> Result<Vec<DeduplicationTarget>, NotEnoughSpace> {
+    let files = regular_files(paths, &ctx.progress);
+    ctx.progress.set_discovery_message("Measuring");
+    let mut filesystems = BTreeMap::<u64, FilesystemSpace>::new();
+    // Filesystem IDs by `st_dev`, as btrfs subvolumes share their filesystem's free space.
+    let mut filesystem_ids = HashMap::<u64, u64>::new();
+    let mut targets = Vec::new();
+    for path in files {
+        if ctx.is_interrupted() {
+            return Ok(targets);
+        }
+        match measure(&path, &mut filesystem_ids, &mut filesystems) {
+            Ok(false) => {}
+            Ok(true) => targets.push(DeduplicationTarget::Unshare(path)),
+            // Still try it, so the failure is counted and reported with the others.
+            Err(e) => {
+                log_scan_error(&path, e);
+                targets.push(DeduplicationTarget::Unshare(path));
+            }
+        }
+        ctx.progress.target_discovered();
+    }
+
+    if filesystems.is_empty() {
+        info!("Nothing is shared, so unsharing needs no free space.");
+    }
+    let mut insufficient = false;
+    for space in filesystems.values() {
+        info!(
+            path:% = space.example.display(), bytes_needed = space.needed,
+            bytes_available = space.available;
+            "Unsharing needs up to {} of free space on the filesystem holding {}, which has {}.",
+            HumanBytes(space.needed), space.example.display(), HumanBytes(space.available)
+        );
+        if space.needed > space.available {
+            error!(
+                path:% = space.example.display(), bytes_needed = space.needed,
+                bytes_available = space.available;
+                "Not enough free space to unshare files on the filesystem holding {}.",
+                space.example.display()
+            );
+            insufficient = true;
+        }
+    }
+    if insufficient && !ctx.dry_run {
+        return Err(NotEnoughSpace);
+    }
+    Ok(targets)
+}
+
+/// Add the free space unsharing [path] needs to its filesystem's share. Returns whether anything
+/// in it is shared.
+fn measure(
+    path: &Path,
+    filesystem_ids: &mut HashMap<u64, u64>,
+    filesystems: &mut BTreeMap<u64, FilesystemSpace>,
+) -> Result<bool, std::io::Error> {
+    let (file, shared) = shared_bytes(path)?;
+    if shared == 0 {
+        return Ok(false);
+    }
+    let metadata = file.metadata()?;
+    let id = match filesystem_ids.entry(metadata.dev()) {
+        hash_map::Entry::Occupied(entry) => *entry.get(),
+        hash_map::Entry::Vacant(entry) => *entry.insert(filesystem_id(&file)?),
+    };
+    let space = match filesystems.entry(id) {
+        btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        btree_map::Entry::Vacant(entry) => entry.insert(FilesystemSpace {
+            example: path.to_path_buf(),
+            in_place: unshares_in_place(&file)?,
+            needed: 0,
+            available: available_space(&file)?,
+        }),
+    };
+    space.needed += if space.in_place {
+        shared
+    } else {
+        metadata.blocks() * 512
+    };
+    Ok(true)
+}
+
+fn log_scan_error(path: &Path, e: std::io::Error) {
+    error!(
+        path:% = path.display(), errno = e.raw_os_error();
+        "Failed to read {}: {}", path.display(), e
+    );
+}
+
+/// Open [path], and count the bytes of it that are shared.
+fn shared_bytes(path: &Path) -> Result<(File, u64), std::io::Error> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.size();
+    let shared = shared_ranges(&file, size)?
+        .iter()
+        .map(|r| r.end - r.start)
+        .sum();
+    Ok((file, shared))
+}
+
+/// The parts of the first [size] bytes of [file] that are flagged as shared.
+fn shared_ranges(file: &File, size: u64) -> Result<Vec<Range<u64>>, std::io::Error> {
+    // Sync first, so data that's still being written out has extents to look at.
+    let extents = get_extents(file, 0..size, MapFlags::SYNC)?;
+    Ok(merge_ranges(
+        extents
+            .iter()
+            .filter(|e| e.flags.contains(&ExtentFlag::Shared))
+            .filter_map(|e| e.clip(0..size))
+            .map(|e| e.logical_offset..(e.logical_offset + e.length)),
+    ))
+}
+
+pub async fn process_unshare(ctx: &DedupeContext, path: PathBuf) -> UnshareResult {
+    let result = tokio::task::spawn_blocking({
+        let ctx = ctx.clone();
+        let path = path.clone();
+        move || unshare_file(&ctx, &path)
+    })
+    .await
+    .expect("failed to spawn blocking");
+    result.map_err(|e| DedupeError {
+        target: DeduplicationTarget::Unshare(path),
+        source: e,
+    })
+}
+
+/// How many bytes of [path] are shared, for `--prioritize`.
+pub async fn estimate_unshare(path: &Path) -> Result<u64, std::io::Error> {
+    let path = path.to_path_buf();
+    let (_, shared) = tokio::task::spawn_blocking(move || shared_bytes(&path))
+        .await
+        .expect("failed to spawn blocking")?;
+    Ok(shared)
+}
+
+/// Give the shared parts of [path] storage of their own, in place if the filesystem can,
+/// otherwise by replacing the file with a copy. Returns `None` if too little is shared.
+fn unshare_file(ctx: &DedupeContext, path: &Path) -> Result<Option<UnshareInfo>, std::io::Error> {
+    let start = Instant::now();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!ctx.dry_run)
+        .open(path)?;
+    let metadata = file.metadata()?;
+    let size = metadata.size();
+    let shared = shared_ranges(&file, size)?;
+    let bytes_shared = shared.iter().map(|r| r.end - r.start).sum::<u64>();
+    if bytes_shared == 0 || bytes_shared < ctx.min_savings {
+        return Ok(None);
+    }
+    let mut info = UnshareInfo {
+        path: path.to_path_buf(),
+        size,
+        shared_ranges: shared,
+        bytes_shared,
+        bytes_unshared: None,
+        copied: false,
+        duration: Duration::ZERO,
+    };
+    if ctx.dry_run {
+        info.duration = start.elapsed();
+        return Ok(Some(info));
+    }
+
+    ctx.progress.bytes_found(bytes_shared);
+    let dev = metadata.dev();
+    let mut unshared = 0;
+    for range in &info.shared_ranges {
+        // Finish the current range when interrupted, but don't start any more.
+        if ctx.is_interrupted() {
+            break;
+        }
+        let len = range.end - range.start;
+        if !ctx.throttle.charge(&[(dev, len)], || ctx.is_interrupted()) {
+            break;
+        }
+        ctx.budget.bytes_compared.fetch_add(len, Ordering::Relaxed);
+        debug!(
+            path:% = path.display(), offset = range.start, len;
+            "Unsharing range"
+        );
+        match unshare_range(&file, range.clone()) {
+            Ok(()) => unshared += len,
+            // Nothing has been unshared yet, so copying the whole file is the only way.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) && unshared == 0 => {
+                warn!(
+                    path:% = path.display();
+                    "Can't unshare {} in place, replacing it with a copy", path.display()
+                );
+                drop(file);
+                copy_file(ctx, path, &metadata)?;
+                unshared = bytes_shared;
+                info.copied = true;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    info.bytes_unshared = Some(unshared);
+    info.duration = start.elapsed();
+    Ok(Some(info))
+}
+
+/// Replace [path] with a copy of itself, if there's room for one.
+fn copy_file(
+    ctx: &DedupeContext,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<(), std::io::Error> {
+    // The copy needs room for all of the file's data, not just the shared parts, until the
+    // original is gone.
+    let allocated = metadata.blocks() * 512;
+    let available = available_space(&File::open(path)?)?;
+    if allocated > available {
+        return Err(std::io::Error::new(
+            ErrorKind::StorageFull,
+            format!(
+                "Not enough free space to copy the file, needs {} but only {} is available",
+                HumanBytes(allocated),
+                HumanBytes(available)
+            ),
+        ));
+    }
+    ctx.progress.bytes_found(metadata.size());
+    if !ctx
+        .throttle
+        .charge(&[(metadata.dev(), metadata.size())], || {
+            ctx.is_interrupted()
+        })
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::Interrupted,
+            "Interrupted before the file was copied",
+        ));
+    }
+    ctx.budget
+        .bytes_compared
+        .fetch_add(metadata.size(), Ordering::Relaxed);
+    copy_and_replace(path)
+}
+
+pub fn print_unshare(result: UnshareResult) {
+    match result {
+        Ok(Some(info)) => match info.bytes_unshared {
+            Some(unshared) => info!(
+                path:% = info.path.display(), len = info.size,
+                ranges = info.shared_ranges.len(), bytes_unshared = unshared,
+                copied = info.copied;
+                "==> Unshared {} of {}{}",
+                HumanBytes(unshared), info.path.display(),
+                if info.copied { " by copying it" } else { "" }
+            ),
+            None => info!(
+                path:% = info.path.display(), len = info.size,
+                ranges = info.shared_ranges.len(), bytes_shared = info.bytes_shared;
+                "==> Would unshare {} of {}",
+                HumanBytes(info.bytes_shared), info.path.display()
+            ),
+        },
+        Ok(None) => {}
+        Err(e) => print_dedupe_error(e),
+    }
+}
@@ -0,0 +1,419 @@
+#![deny(warnings)]
+//! A re-implementation of the `filefrag` command using Rust. The output follows e2fsprogs'
+//! `filefrag`, so that scripts written for it keep working.
+
+mod stats;
+mod who_shares;
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde::ser::{Error, SerializeSeq};
+use serde::{Serialize, Serializer};
+
+use dedupetool::diskblade::serialize_path_lossy;
+use dedupetool::ioctl_fiemap::{get_extents, Extent, ExtentFlag, ExtentIter, MapFlags};
+
+use crate::stats::{FileExtents, FragmentationStats, Summary};
+use crate::who_shares::SharingIndex;
+
+/// filefrag command, reporting how files are laid out on disk.
+#[derive(Parser)]
+#[clap(name = "filefrag-rs", version)]
+struct FileFrag {
+    /// Print the file's size and filesystem type, and every extent.
+    #[clap(short, long)]
+    verbose: bool,
+    /// Print every extent, in a table. Like `filefrag -e`, this also prints what `-v` does.
+    #[clap(short = 'e', long = "extents")]
+    extent_format: bool,
+    /// Show offsets and lengths in blocks of this many bytes, rather than the filesystem's block
+    /// size. Defaults to 1024 when given without a value, e.g. `-b` or `-b4096`.
+    #[clap(
+        short = 'b',
+        long,
+        value_name = "BLOCKSIZE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1024",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    block_size: Option<u64>,
+    /// Print each file's size and extents as JSON, one object per line. Offsets and lengths are
+    /// in bytes.
+    #[clap(long, conflicts_with_all = ["verbose", "extent_format", "block_size"])]
+    json: bool,
+    /// Write out the file's dirty data before mapping it, so delayed allocations have a location.
+    #[clap(short, long)]
+    sync: bool,
+    /// Map the blocks holding the file's extended attributes, rather than its data.
+    #[clap(short = 'x', long)]
+    xattr: bool,
+    /// Have the filesystem load the file's whole extent tree into its cache first. Only some
+    /// filesystems, such as ext4, support this.
+    #[clap(short = 'P', long = "precache")]
+    cache: bool,
+    /// Walk into directories, and print statistics on how fragmented the files are at the end.
+    /// Symlinks aren't followed.
+    #[clap(short, long)]
+    recursive: bool,
+    /// Instead of the files' extents, list the files under this directory that share physical
+    /// extents with them, and where. Only files on the same filesystem are searched, which takes
+    /// in its other btrfs subvolumes and snapshots.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["verbose", "extent_format", "recursive"]
+    )]
+    who_shares: Option<PathBuf>,
+    /// The files to print information for.
+    #[clap(num_args = 1..)]
+    files: Vec<PathBuf>,
+}
+
+impl FileFrag {
+    fn map_flags(&self) -> MapFlags {
+        let mut flags = MapFlags::NONE;
+        if self.sync {
+            flags |= MapFlags::SYNC;
+        }
+        if self.xattr {
+            flags |= MapFlags::XATTR;
+        }
+        if self.cache {
+            flags |= MapFlags::CACHE;
+        }
+        flags
+    }
+}
+
+fn main() {
+    let mut options_ended = false;
+    let args: FileFrag = FileFrag::parse_from(std::env::args_os().map(|arg| {
+        // filefrag takes `-b4096`, but clap would then take `-b <file>` as a block size too.
+        // Only taking `-b=4096` avoids that, so accept the filefrag form by rewriting it.
+        // Everything after `--` is a file, whatever it looks like.
+        match arg.to_str() {
+            Some("--") => {
+                options_ended = true;
+                arg
+            }
+            Some(s)
+                if !options_ended
+                    && s.starts_with("-b")
+                    && s[2..].starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                format!("-b={}", &s[2..]).into()
+            }
+            _ => arg,
+        }
+    }));
+
+    if let Some(dir) = &args.who_shares {
+        who_shares(&args, dir);
+    }
+
+    let mut any_failed = false;
+    let mut stats = args.recursive.then(FragmentationStats::default);
+    let mut visit = |path: &Path| match print_file(&args, path) {
+        Ok(extents) => {
+            if let Some(stats) = &mut stats {
+                stats.add(path, &extents);
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to print information for {}: {}", path.display(), e);
+            any_failed = true;
+        }
+    };
+    for path in &args.files {
+        if args.recursive && path.is_dir() {
+            walk(path, &mut visit, &mut |path, e| {
+                eprintln!("Failed to read directory {}: {}", path.display(), e);
+            });
+        } else {
+            visit(path);
+        }
+    }
+    if let Some(stats) = &stats {
+        if args.json {
+            let record = StatsRecord {
+                stats: stats.summary(),
+            };
+            match serde_json::to_string(&record) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    eprintln!("Failed to print statistics: {}", e);
+                    any_failed = true;
+                }
+            }
+        } else {
+            stats.print();
+        }
+    }
+    if any_failed {
+        std::process::exit(1);
+    }
+}
+
+fn who_shares(args: &FileFrag, dir: &Path) -> ! {
+    let index = match SharingIndex::build(dir, args.map_flags()) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Failed to index {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let mut any_failed = false;
+    for path in &args.files {
+        if let Err(e) = index.print_file(path, args.map_flags(), args.block_size, args.json) {
+            eprintln!("Failed to print information for {}: {}", path.display(), e);
+            any_failed = true;
+        }
+    }
+    std::process::exit(any_failed as i32);
+}
+
+/// Visit every regular file under [dir], in name order. Symlinks aren't followed.
+fn walk(dir: &Path, visit: &mut dyn FnMut(&Path), on_error: &mut dyn FnMut(&Path, std::io::Error)) {
+    let entries = match std::fs::read_dir(dir).and_then(|d| d.collect::<Result<Vec<_>, _>>()) {
+        Ok(entries) => entries,
+        Err(e) => return on_error(dir, e),
+    };
+    let mut entries = entries
+        .into_iter()
+        .filter_map(|entry| match entry.file_type() {
+            Ok(file_type) => Some((entry.path(), file_type)),
+            Err(e) => {
+                on_error(&entry.path(), e);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, file_type) in entries {
+        if file_type.is_dir() {
+            walk(&path, visit, on_error);
+        } else if file_type.is_file() {
+            visit(&path);
+        }
+    }
+}
+
+fn print_file(args: &FileFrag, path: &Path) -> Result<FileExtents, std::io::Error> {
+    if args.json {
+        return print_file_json(path, args.map_flags());
+    }
+    let file = std::fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let fs = statfs(&file)?;
+    let fs_block_size = fs.f_bsize as u64;
+    let block_size = args.block_size.unwrap_or(fs_block_size);
+    let size = metadata.size();
+    // Like filefrag, count whole filesystem blocks, then convert them to the blocks shown.
+    let fs_blocks = size.div_ceil(fs_block_size);
+    let blocks = fs_blocks * fs_block_size / block_size;
+
+    let verbose = args.verbose || args.extent_format;
+    if verbose {
+        println!("Filesystem type is: {:x}", fs.f_type);
+        println!(
+            "File size of {} is {} ({} block{} of {} bytes)",
+            path.display(),
+            size,
+            blocks,
+            if blocks == 1 { "" } else { "s" },
+            block_size
+        );
+    }
+
+    let extents = get_extents(&file, 0..u64::MAX, args.map_flags())?;
+    // The columns are sized in filesystem blocks, whatever the blocks shown.
+    let table = verbose.then(|| ExtentTable {
+        block_size,
+        file_size: size,
+        logical_width: int_log10(fs_blocks).max(8),
+        physical_width: int_log10(fs.f_blocks).max(10),
+    });
+    // Like filefrag, there's no header for a file without any extents.
+    if let (Some(table), false) = (&table, extents.is_empty()) {
+        table.print_header();
+    }
+
+    let mut counted = FileExtents::default();
+    for (i, extent) in extents.iter().enumerate() {
+        let expected = counted.add(extent);
+        if let Some(table) = &table {
+            table.print_extent(i, extent, expected);
+        }
+    }
+
+    let count = counted.count();
+    println!(
+        "{}: {} extent{} found",
+        path.display(),
+        count,
+        if count == 1 { "" } else { "s" }
+    );
+    Ok(counted)
+}
+
+/// A file's extent map, as printed by `--json`.
+#[derive(Serialize)]
+struct FileRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    size: u64,
+    extents: StreamedExtents<'a>,
+}
+
+/// Extents serialized as they're read, so a file's extents aren't all kept around at once.
+/// They're added to [counted] along the way.
+struct StreamedExtents<'a> {
+    extents: RefCell<ExtentIter<'a>>,
+    counted: RefCell<FileExtents>,
+}
+
+impl Serialize for StreamedExtents<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for extent in &mut *self.extents.borrow_mut() {
+            let extent = extent.map_err(S::Error::custom)?;
+            self.counted.borrow_mut().add(&extent);
+            seq.serialize_element(&extent)?;
+        }
+        seq.end()
+    }
+}
+
+/// The statistics at the end of `-r --json`.
+#[derive(Serialize)]
+struct StatsRecord<'a> {
+    stats: Summary<'a>,
+}
+
+fn print_file_json(path: &Path, flags: MapFlags) -> Result<FileExtents, std::io::Error> {
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.size();
+    let record = FileRecord {
+        path,
+        size,
+        extents: StreamedExtents {
+            extents: RefCell::new(ExtentIter::new(&file, 0..u64::MAX, flags)),
+            counted: RefCell::default(),
+        },
+    };
+    // Printed only once every extent has been read, so a failure doesn't leave half a line.
+    println!("{}", serde_json::to_string(&record)?);
+    Ok(record.extents.counted.into_inner())
+}
+
+/// The `-e`/`-v` table of extents, in the units and column widths filefrag uses.
+struct ExtentTable {
+    block_size: u64,
+    file_size: u64,
+    logical_width: usize,
+    physical_width: usize,
+}
+
+impl ExtentTable {
+    fn print_header(&self) {
+        println!(
+            " ext: {:>lw$} {:>pw$} length: {:>ew$} flags:",
+            "logical_offset:",
+            "physical_offset:",
+            "expected:",
+            lw = self.logical_width * 2 + 3,
+            pw = self.physical_width * 2 + 3,
+            ew = self.physical_width + 1,
+        );
+    }
+
+    fn print_extent(&self, index: usize, extent: &Extent, expected: Option<u64>) {
+        // Inline data doesn't have block aligned offsets, so is shown in bytes.
+        let block_size = if extent.flags.contains(&ExtentFlag::DataInline) {
+            1
+        } else {
+            self.block_size
+        };
+        let last_block = extent.length.saturating_sub(1) / block_size;
+        let logical = extent.logical_offset / block_size;
+        // An extent without a location is shown as an empty one at the start of the disk.
+        let (physical, physical_last_block, length) =
+            if extent.flags.contains(&ExtentFlag::LocationUnknown) {
+                (0, 0, 0)
+            } else {
+                (
+                    extent.physical_offset / block_size,
+                    last_block,
+                    extent.length / block_size,
+                )
+            };
+        let mut rest = match expected {
+            Some(expected) => format!("{:>w$}: ", expected / block_size, w = self.physical_width),
+            None => " ".repeat(self.physical_width + 2),
+        };
+        for name in flag_names(&extent.flags) {
+            rest += &name;
+            rest.push(',');
+        }
+        if extent.logical_offset + extent.length >= self.file_size {
+            rest += "eof,";
+        }
+        // filefrag drops the last character, meant to be a trailing comma, even without flags.
+        rest.pop();
+        println!(
+            "{:>4}: {:>lw$}..{:>lw$}: {:>pw$}..{:>pw$}: {:>6}: {}",
+            index,
+            logical,
+            logical + last_block,
+            physical,
+            physical + physical_last_block,
+            length,
+            rest,
+            lw = self.logical_width,
+            pw = self.physical_width,
+        );
+    }
+}
+
+/// The flags as filefrag names them.
+fn flag_names(flags: &BTreeSet<ExtentFlag>) -> impl Iterator<Item = String> + '_ {
+    flags.iter().map(|flag| match flag {
+        ExtentFlag::Last => "last".to_string(),
+        ExtentFlag::LocationUnknown => "unknown_loc".to_string(),
+        ExtentFlag::DelayedAllocation => "delalloc".to_string(),
+        ExtentFlag::Encoded => "encoded".to_string(),
+        ExtentFlag::DataEncrypted => "encrypted".to_string(),
+        ExtentFlag::NotAligned => "not_aligned".to_string(),
+        ExtentFlag::DataInline => "inline".to_string(),
+        ExtentFlag::DataTail => "tail_packed".to_string(),
+        ExtentFlag::Unwritten => "unwritten".to_string(),
+        ExtentFlag::Merged => "merged".to_string(),
+        ExtentFlag::Shared => "shared".to_string(),
+        ExtentFlag::Unknown(flag) => format!("{:#04x}", flag),
+    })
+}
+
+/// The whole part of log10 of [n], or 0 for 0. filefrag sizes its columns by this.
+fn int_log10(mut n: u64) -> usize {
+    let mut log = 0;
+    while n >= 10 {
+        n /= 10;
+        log += 1;
+    }
+    log
+}
+
+fn statfs(file: &std::fs::File) -> Result<libc::statfs, std::io::Error> {
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
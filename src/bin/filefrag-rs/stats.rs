@@ -0,0 +1,218 @@
+//! Fragmentation statistics across many files, for `-r`.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::path::{Path, PathBuf};
+
+use indicatif::{HumanBytes, HumanCount};
+use serde::Serialize;
+
+use dedupetool::diskblade::serialize_path_lossy;
+use dedupetool::ioctl_fiemap::{Extent, ExtentFlag};
+
+/// How many of the most fragmented files to list.
+const MOST_FRAGMENTED: usize = 10;
+
+/// The flags worth knowing the share of bytes for, and what to call them.
+const TRACKED_FLAGS: [(ExtentFlag, &str); 4] = [
+    (ExtentFlag::Shared, "shared"),
+    (ExtentFlag::Unwritten, "unwritten"),
+    (ExtentFlag::DataInline, "inline"),
+    (ExtentFlag::Encoded, "encoded"),
+];
+
+#[derive(Default)]
+pub struct FragmentationStats {
+    files: u64,
+    /// Extents as reported by FIEMAP, including ones that follow on from the previous one.
+    extents: u64,
+    /// Bytes in all extents.
+    extent_bytes: u64,
+    /// Bytes in extents with each of [TRACKED_FLAGS].
+    flag_bytes: [u64; TRACKED_FLAGS.len()],
+    /// Files by their extent count, bucketed by powers of two.
+    histogram: BTreeMap<u64, u64>,
+    /// The most fragmented files, least fragmented on top so it can be popped off.
+    most_fragmented: BinaryHeap<Reverse<(u64, PathBuf)>>,
+}
+
+/// The statistics, as printed by `--json`.
+#[derive(Serialize)]
+pub struct Summary<'a> {
+    files: u64,
+    extents: u64,
+    average_extent_size: f64,
+    /// The share of bytes in extents with each flag, from 0 to 1.
+    flag_fractions: BTreeMap<&'static str, f64>,
+    histogram: Vec<Bucket>,
+    most_fragmented: Vec<Fragmented<'a>>,
+}
+
+/// Files with between [min] and [max] extents, inclusive.
+#[derive(Serialize)]
+struct Bucket {
+    min: u64,
+    max: u64,
+    files: u64,
+}
+
+#[derive(Serialize)]
+struct Fragmented<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    extents: u64,
+}
+
+/// One file's extents, added up as they're read.
+#[derive(Default)]
+pub struct FileExtents {
+    /// Extents as reported by FIEMAP, including ones that follow on from the previous one.
+    extents: u64,
+    /// Extents that don't follow on from the previous one, as filefrag counts them.
+    fragments: u64,
+    extent_bytes: u64,
+    flag_bytes: [u64; TRACKED_FLAGS.len()],
+    /// The logical offset, physical offset and length of the previous extent.
+    last: (u64, u64, u64),
+}
+
+impl FileExtents {
+    /// Add the file's next extent. Returns where it would have started if it followed on from
+    /// the previous one, or `None` if it does. filefrag compares the first extent against an
+    /// empty one at the start of the disk.
+    pub fn add(&mut self, extent: &Extent) -> Option<u64> {
+        let (last_logical, last_physical, last_length) = self.last;
+        let expected = last_physical + extent.logical_offset - last_logical;
+        let expected_dense = last_physical + last_length;
+        let expected = (extent.logical_offset != 0
+            && extent.physical_offset != expected
+            && extent.physical_offset != expected_dense)
+            .then_some(expected);
+        // The first extent is always counted, even if it's where one would be expected.
+        if expected.is_some() || self.extents == 0 {
+            self.fragments += 1;
+        }
+        self.last = (extent.logical_offset, extent.physical_offset, extent.length);
+        self.extents += 1;
+        self.extent_bytes += extent.length;
+        for ((flag, _), bytes) in TRACKED_FLAGS.iter().zip(&mut self.flag_bytes) {
+            if extent.flags.contains(flag) {
+                *bytes += extent.length;
+            }
+        }
+        expected
+    }
+
+    /// The number of extents, counted the way filefrag does, as shown to the user.
+    pub fn count(&self) -> u64 {
+        self.fragments
+    }
+}
+
+impl FragmentationStats {
+    /// Count a file, with its [extents].
+    pub fn add(&mut self, path: &Path, extents: &FileExtents) {
+        let count = extents.count();
+        self.files += 1;
+        self.extents += extents.extents;
+        self.extent_bytes += extents.extent_bytes;
+        for (total, bytes) in self.flag_bytes.iter_mut().zip(extents.flag_bytes) {
+            *total += bytes;
+        }
+        *self.histogram.entry(bucket_min(count)).or_default() += 1;
+
+        self.most_fragmented
+            .push(Reverse((count, path.to_path_buf())));
+        if self.most_fragmented.len() > MOST_FRAGMENTED {
+            self.most_fragmented.pop();
+        }
+    }
+
+    pub fn summary(&self) -> Summary<'_> {
+        let mut most_fragmented = self
+            .most_fragmented
+            .iter()
+            .map(|Reverse((extents, path))| Fragmented {
+                path,
+                extents: *extents,
+            })
+            .collect::<Vec<_>>();
+        most_fragmented.sort_by(|a, b| b.extents.cmp(&a.extents).then(a.path.cmp(b.path)));
+        Summary {
+            files: self.files,
+            extents: self.extents,
+            average_extent_size: match self.extents {
+                0 => 0.0,
+                extents => self.extent_bytes as f64 / extents as f64,
+            },
+            flag_fractions: TRACKED_FLAGS
+                .iter()
+                .zip(self.flag_bytes)
+                .map(|((_, name), bytes)| (*name, fraction(bytes, self.extent_bytes)))
+                .collect(),
+            histogram: self
+                .histogram
+                .iter()
+                .map(|(min, files)| Bucket {
+                    min: *min,
+                    max: bucket_max(*min),
+                    files: *files,
+                })
+                .collect(),
+            most_fragmented,
+        }
+    }
+
+    pub fn print(&self) {
+        let summary = self.summary();
+        println!();
+        println!(
+            "{} files, {} extents, averaging {} per extent",
+            HumanCount(summary.files),
+            HumanCount(summary.extents),
+            HumanBytes(summary.average_extent_size as u64)
+        );
+        println!("Files by extent count:");
+        for bucket in &summary.histogram {
+            let range = match bucket.min == bucket.max {
+                true => bucket.min.to_string(),
+                false => format!("{}-{}", bucket.min, bucket.max),
+            };
+            println!("{:>15}: {}", range, HumanCount(bucket.files));
+        }
+        println!("Bytes in extents that are:");
+        for (name, fraction) in &summary.flag_fractions {
+            println!("{:>15}: {:.1}%", name, fraction * 100.0);
+        }
+        println!("Most fragmented files:");
+        for file in &summary.most_fragmented {
+            println!(
+                "{:>15}: {}",
+                HumanCount(file.extents).to_string(),
+                file.path.display()
+            );
+        }
+    }
+}
+
+/// The smallest extent count in [count]'s bucket. Buckets are 0, 1, 2-3, 4-7 and so on.
+fn bucket_min(count: u64) -> u64 {
+    match count {
+        0 => 0,
+        count => 1 << count.ilog2(),
+    }
+}
+
+fn bucket_max(min: u64) -> u64 {
+    match min {
+        0 => 0,
+        min => min.saturating_mul(2) - 1,
+    }
+}
+
+fn fraction(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64,
+    }
+}
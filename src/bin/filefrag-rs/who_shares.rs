@@ -0,0 +1,219 @@
+//! `--who-shares`: which files under a directory share physical extents with each file, to find
+//! out why deleting something didn't free any space.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use dedupetool::device::filesystem_id;
+use dedupetool::diskblade::serialize_path_lossy;
+use dedupetool::ioctl_fiemap::{Extent, ExtentIter, MapFlags};
+use dedupetool::physical::{PhysicalIndex, PhysicalRangeSet};
+
+use crate::walk;
+
+/// The extents of every file under a directory, by where they are on disk.
+pub struct SharingIndex {
+    /// The filesystem the directory is on. Physical offsets only mean anything within one
+    /// filesystem, which can have subvolumes with `st_dev`s of their own.
+    filesystem: u64,
+    /// The filesystem of each `st_dev` seen, so it only has to be looked up once.
+    filesystems: HashMap<u64, u64>,
+    files: Vec<IndexedFile>,
+    /// Extents, owned by their index in [files].
+    index: PhysicalIndex<usize>,
+}
+
+struct IndexedFile {
+    path: PathBuf,
+    ino: u64,
+}
+
+/// A file's shared extents, as printed by `--json`.
+#[derive(Serialize)]
+struct SharingRecord<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    size: u64,
+    /// Bytes of the file that are also used by other files, or elsewhere in the same file.
+    shared_bytes: u64,
+    extents: Vec<SharedExtent<'a>>,
+}
+
+#[derive(Serialize)]
+struct SharedExtent<'a> {
+    #[serde(flatten)]
+    extent: Extent,
+    shared_with: Vec<Sharer<'a>>,
+}
+
+#[derive(Serialize)]
+struct Sharer<'a> {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    path: &'a Path,
+    logical_offset: u64,
+    length: u64,
+}
+
+impl SharingIndex {
+    /// Index every regular file under [dir] that's on the same filesystem. Files that can't be
+    /// read are reported and left out.
+    pub fn build(dir: &Path, flags: MapFlags) -> Result<SharingIndex, std::io::Error> {
+        let filesystem = filesystem_id(&File::open(dir)?)?;
+        let mut sharing = SharingIndex {
+            filesystem,
+            filesystems: HashMap::new(),
+            files: Vec::new(),
+            index: PhysicalIndex::new(),
+        };
+        walk(
+            dir,
+            &mut |path| {
+                if let Err(e) = sharing.add(path, flags) {
+                    eprintln!("Failed to index {}: {}", path.display(), e);
+                }
+            },
+            &mut |path, e| {
+                eprintln!("Failed to read directory {}: {}", path.display(), e);
+            },
+        );
+        Ok(sharing)
+    }
+
+    fn add(&mut self, path: &Path, flags: MapFlags) -> Result<(), std::io::Error> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let filesystem = match self.filesystems.get(&metadata.dev()) {
+            Some(filesystem) => *filesystem,
+            None => {
+                let filesystem = filesystem_id(&file)?;
+                self.filesystems.insert(metadata.dev(), filesystem);
+                filesystem
+            }
+        };
+        if filesystem != self.filesystem {
+            return Ok(());
+        }
+        let owner = self.files.len();
+        for extent in ExtentIter::new(&file, 0..u64::MAX, flags) {
+            self.index.insert(owner, &extent?);
+        }
+        self.files.push(IndexedFile {
+            path: path.to_path_buf(),
+            ino: metadata.ino(),
+        });
+        Ok(())
+    }
+
+    /// Print what shares storage with the file at [path], in blocks of [block_size] bytes, or
+    /// in bytes as JSON if [json].
+    pub fn print_file(
+        &self,
+        path: &Path,
+        flags: MapFlags,
+        block_size: Option<u64>,
+        json: bool,
+    ) -> Result<(), std::io::Error> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if filesystem_id(&file)? != self.filesystem {
+            return Err(std::io::Error::other(
+                "Not on the same filesystem as the directory searched",
+            ));
+        }
+        let canonical = path.canonicalize()?;
+        // Hard links share an inode, so only the same path is the file itself.
+        let is_self = |owner: &IndexedFile| {
+            owner.ino == metadata.ino() && owner.path.canonicalize().is_ok_and(|p| p == canonical)
+        };
+
+        let mut shared = PhysicalRangeSet::new();
+        let mut sharers = BTreeSet::new();
+        let mut shared_extents = Vec::new();
+        for extent in ExtentIter::new(&file, 0..u64::MAX, flags) {
+            // The end of the last block isn't part of the file, so isn't counted as shared.
+            let Some(extent) = extent?.clip(0..metadata.size()) else {
+                continue;
+            };
+            let Some(physical) = extent.physical_range() else {
+                continue;
+            };
+            let shared_with = self
+                .index
+                .overlapping(physical)
+                .filter(|mapping| {
+                    // The file's own extent isn't sharing with anything.
+                    !is_self(&self.files[*mapping.owner])
+                        || mapping.logical.start
+                            != extent.logical_offset
+                                + (mapping.physical.start - extent.physical_offset)
+                })
+                .map(|mapping| {
+                    shared.insert(mapping.physical.clone());
+                    sharers.insert(*mapping.owner);
+                    Sharer {
+                        path: &self.files[*mapping.owner].path,
+                        logical_offset: mapping.logical.start,
+                        length: mapping.logical.end - mapping.logical.start,
+                    }
+                })
+                .collect::<Vec<_>>();
+            if !shared_with.is_empty() {
+                shared_extents.push(SharedExtent {
+                    extent,
+                    shared_with,
+                });
+            }
+        }
+
+        if json {
+            let record = SharingRecord {
+                path,
+                size: metadata.size(),
+                shared_bytes: shared.len(),
+                extents: shared_extents,
+            };
+            println!("{}", serde_json::to_string(&record)?);
+            return Ok(());
+        }
+
+        let block_size = match block_size {
+            Some(block_size) => block_size,
+            None => crate::statfs(&file)?.f_bsize as u64,
+        };
+        let blocks = |offset: u64, length: u64| {
+            format!(
+                "{}..{}",
+                offset / block_size,
+                (offset + length).saturating_sub(1) / block_size
+            )
+        };
+        println!(
+            "{}: {} of {} bytes shared with {} file{}",
+            path.display(),
+            shared.len(),
+            metadata.size(),
+            sharers.len(),
+            if sharers.len() == 1 { "" } else { "s" }
+        );
+        for shared_extent in &shared_extents {
+            let extent = &shared_extent.extent;
+            println!(
+                "  {} (physical {}):",
+                blocks(extent.logical_offset, extent.length),
+                blocks(extent.physical_offset, extent.length)
+            );
+            for sharer in &shared_extent.shared_with {
+                println!(
+                    "    {}: {}",
+                    sharer.path.display(),
+                    blocks(sharer.logical_offset, sharer.length)
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Helpers for figuring out which device a file lives on.
+
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Get the device ID (`st_dev`) for [path]. If [path] is itself a block device node, the ID of
+/// the device it represents (`st_rdev`) is returned instead, so that `/dev/sda` and a file stored
+/// on it resolve to the same ID.
+pub fn device_id<P: AsRef<Path>>(path: P) -> Result<u64, std::io::Error> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.file_type().is_block_device() {
+        Ok(metadata.st_rdev())
+    } else {
+        Ok(metadata.st_dev())
+    }
+}
+
+/// An ID for the filesystem holding [file], to tell whether physical offsets from FIEMAP can be
+/// compared.
+///
+/// Unlike `st_dev`, it's the same for every subvolume and snapshot of a btrfs filesystem: it's
+/// the device ID of the superblock the file's mount is of, from `/proc/self/mountinfo`. Falls back
+/// to `st_dev` on kernels without `STATX_MNT_ID`.
+pub fn filesystem_id(file: &File) -> Result<u64, std::io::Error> {
+    let mut stat = MaybeUninit::<libc::statx>::uninit();
+    if unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_MNT_ID,
+            stat.as_mut_ptr(),
+        )
+    } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    let st_dev = libc::makedev(stat.stx_dev_major, stat.stx_dev_minor);
+    if stat.stx_mask & libc::STATX_MNT_ID == 0 {
+        return Ok(st_dev);
+    }
+    Ok(mount_device(stat.stx_mnt_id).unwrap_or(st_dev))
+}
+
+/// Find the device ID of the superblock mounted as [mount_id].
+fn mount_device(mount_id: u64) -> Option<u64> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        // See proc(5): the mount ID is the 1st field, and the device the 3rd.
+        let mut fields = line.split(' ');
+        if fields.next()?.parse::<u64>().ok()? != mount_id {
+            return None;
+        }
+        let (major, minor) = fields.nth(1)?.split_once(':')?;
+        Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+    })
+}
+
+/// The block device backing a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockDevice {
+    /// The device ID of the block device, or of the filesystem if no block device was found.
+    pub id: u64,
+    /// Whether the device is rotational, if it could be determined.
+    pub rotational: Option<bool>,
+}
+
+impl BlockDevice {
+    /// Resolve the filesystem device ID [st_dev] to the block device backing it.
+    ///
+    /// Filesystems such as btrfs report an anonymous device ID (major 0), in which case the
+    /// mount source from `/proc/self/mountinfo` is used instead. When nothing better can be
+    /// found, [st_dev] itself is used.
+    pub fn resolve(st_dev: u64) -> BlockDevice {
+        let id = if unsafe { libc::major(st_dev) } == 0 {
+            mount_source_device(st_dev).unwrap_or(st_dev)
+        } else {
+            st_dev
+        };
+        BlockDevice {
+            id,
+            rotational: is_rotational(id),
+        }
+    }
+}
+
+/// Find the device ID of the mount source for the filesystem with device ID [st_dev].
+fn mount_source_device(st_dev: u64) -> Option<u64> {
+    let (major, minor) = unsafe { (libc::major(st_dev), libc::minor(st_dev)) };
+    let dev_str = format!("{}:{}", major, minor);
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        // See proc(5): the device is the 3rd field, the source is the 2nd field after the `-`.
+        let mut fields = line.split(' ');
+        if fields.nth(2)? != dev_str {
+            return None;
+        }
+        let source = fields.skip_while(|&f| f != "-").nth(2)?;
+        if !source.starts_with('/') {
+            return None;
+        }
+        device_id(source).ok()
+    })
+}
+
+/// Read `queue/rotational` for the block device [id] from sysfs.
+/// Partitions don't have a queue of their own, so their parent device is checked.
+fn is_rotational(id: u64) -> Option<bool> {
+    let (major, minor) = unsafe { (libc::major(id), libc::minor(id)) };
+    let sys_path = Path::new("/sys/dev/block")
+        .join(format!("{}:{}", major, minor))
+        .canonicalize()
+        .ok()?;
+    let queue_dir = if sys_path.join("partition").exists() {
+        sys_path.parent()?.join("queue")
+    } else {
+        sys_path.join("queue")
+    };
+    match std::fs::read_to_string(queue_dir.join("rotational"))
+        .ok()?
+        .trim()
+    {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Serializer};
 
 /// A target to deduplicate.
 #[derive(Debug, Clone)]
@@ -10,9 +12,10 @@ pub struct FileSectionTarget {
 }
 
 /// An offset into a file.
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct FileOffset {
     /// The file this is an offset into.
+    #[serde(serialize_with = "serialize_path_lossy")]
     file: PathBuf,
     /// The offset into the file of the section.
     offset: u64,
@@ -37,3 +40,12 @@ impl FileOffset {
         self.offset
     }
 }
+
+/// Serialize [path] as a string, replacing anything that isn't UTF-8 rather than failing, so one
+/// odd file name doesn't stop a whole report being written.
+pub fn serialize_path_lossy<P: AsRef<Path>, S: Serializer>(
+    path: &P,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
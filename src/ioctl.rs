@@ -1,10 +1,31 @@
 use std::os::raw::c_ulong;
 use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 
 pub fn ioctl<T>(src: &std::fs::File, request: c_ulong, data: &mut T) -> Result<(), std::io::Error> {
-    if unsafe { libc::ioctl(src.as_raw_fd(), request, data) } == -1 {
+    unsafe { ioctl_ptr(src, request, (data as *mut T).cast()) }
+}
+
+/// Like [ioctl], for arguments that go on past the end of a Rust type, such as a header followed
+/// by a variable number of entries.
+///
+/// # Safety
+/// [data] must point to as much memory as [request] will read and write.
+pub(crate) unsafe fn ioctl_ptr(
+    src: &std::fs::File,
+    request: c_ulong,
+    data: *mut libc::c_void,
+) -> Result<(), std::io::Error> {
+    if libc::ioctl(src.as_raw_fd(), request, data) == -1 {
         Err(std::io::Error::last_os_error())
     } else {
         Ok(())
     }
 }
+
+/// The path [file] was opened from, looked up through `/proc/self/fd`. Only meant for logging.
+pub(crate) fn file_path(file: &std::fs::File) -> PathBuf {
+    let fd = file.as_raw_fd();
+    std::fs::read_link(format!("/proc/self/fd/{}", fd))
+        .unwrap_or_else(|_| PathBuf::from(format!("<fd {}>", fd)))
+}
@@ -6,6 +6,8 @@ pub const FILE_DEDUPE_RANGE_DIFFERS: i32 = 0x1;
 pub const FILE_DEDUPE_RANGE_SAME: i32 = 0x0;
 pub const FS_IOC_FIEMAP: c_ulong = 0xc020660b;
 pub const FIEMAP_FLAG_SYNC: u32 = 0x1;
+pub const FIEMAP_FLAG_XATTR: u32 = 0x2;
+pub const FIEMAP_FLAG_CACHE: u32 = 0x4;
 pub const FIEMAP_EXTENT_LAST: u32 = 0x1;
 pub const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
 pub const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
@@ -17,3 +19,11 @@ pub const FIEMAP_EXTENT_DATA_TAIL: u32 = 0x400;
 pub const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
 pub const FIEMAP_EXTENT_MERGED: u32 = 0x1000;
 pub const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+pub const FS_IOC_GETFLAGS: c_ulong = 0x80086601;
+pub const FS_IOC_SETFLAGS: c_ulong = 0x40086602;
+pub const FS_SYNC_FL: i32 = 0x8;
+pub const FS_NODUMP_FL: i32 = 0x40;
+pub const FS_NOATIME_FL: i32 = 0x80;
+pub const FS_COMPR_FL: i32 = 0x4;
+pub const FS_NOCOMP_FL: i32 = 0x400;
+pub const FS_NOCOW_FL: i32 = 0x800000;
@@ -2,15 +2,20 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::fs::OpenOptions;
 use std::hash::Hash;
+use std::io::ErrorKind;
 use std::ops::Range;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
 
-use crate::ioctl::ioctl;
+use log::{log_enabled, trace, Level};
+
+use crate::ioctl::{file_path, ioctl};
 use crate::ioctl_consts::*;
+use crate::sparse::{data_ranges, merge_ranges, subtract_ranges};
 
 /// This is just a number I came up with. The max combined size needs to be less than a page,
 /// so (4096 <page> - 24 <sizeof request internal>) / 32 <sizeof request internal info> = 127
@@ -43,30 +48,123 @@ thread_local! {
 ///
 /// Destination files go in [request], keyed by whatever you wish. Results will be reported
 /// under the same keys.
-#[allow(warnings)]
-pub fn dedupe_files<K: Eq + Hash + Clone>(
+///
+/// [before_chunk] is called before each ioctl is submitted. If it returns `false`, no further
+/// chunks are submitted and the results gathered so far are returned.
+pub fn dedupe_files<K: Eq + Hash + Clone, F: FnMut(&DedupeChunk<K>) -> bool>(
     src: &std::fs::File,
     src_range: Range<u64>,
-    request: HashMap<K, DedupeRequest>,
+    mut request: HashMap<K, DedupeRequest>,
+    mut before_chunk: F,
 ) -> Result<HashMap<K, Vec<DedupeResponse>>, std::io::Error> {
     let metadata = src.metadata()?;
     let block_size = metadata.st_blksize();
+    // Only look up the path if it's going to be logged.
+    let path = if log_enabled!(Level::Trace) {
+        file_path(src).display().to_string()
+    } else {
+        String::new()
+    };
+    let path = path.as_str();
+    /// Round [n] down to a multiple of [align]. Chunks are submitted from here, so that
+    /// filesystems that need block-aligned offsets accept them.
     fn align_down(n: u64, align: u64) -> u64 {
-        n - ((n * align) / align)
-    }
-    fn align_up(n: u64, align: u64) -> u64 {
-        ((n + align - 1) / align) * align
+        n - (n % align)
     }
 
     let full_length = src_range.end - src_range.start;
-    let mut offset = 0;
     let mut aggregate_results = HashMap::<K, Vec<DedupeResponse>>::new();
-    while offset < full_length {
+    // Only submit what's data in at least one file. Comparing holes would just be comparing
+    // zeros, with nothing to free. Ranges are relative to the start of each file's section.
+    let relative = |ranges: Vec<Range<u64>>, start: u64| {
+        ranges
+            .into_iter()
+            .map(|r| (r.start - start)..(r.end - start))
+            .collect::<Vec<_>>()
+    };
+    let src_data = relative(data_ranges(src, src_range.clone())?, src_range.start);
+    let mut all_data = src_data.clone();
+    for (k, r) in &request {
+        let dest = std::fs::File::open(&r.dest)?;
+        let dest_range = r.dest_offset..(r.dest_offset + full_length);
+        let dest_data = relative(data_ranges(&dest, dest_range)?, r.dest_offset);
+        for hole in subtract_ranges(&dest_data, &src_data) {
+            let response = DedupeResponse::SourceHole {
+                range: (r.dest_offset + hole.start)..(r.dest_offset + hole.end),
+            };
+            trace!(
+                path = r.dest.to_string_lossy().as_ref(), offset = r.dest_offset + hole.start,
+                len = hole.end - hole.start, src = path;
+                "FIDEDUPERANGE destination {}", response
+            );
+            aggregate_results
+                .entry(k.clone())
+                .or_default()
+                .push(response);
+        }
+        all_data.extend(dest_data);
+    }
+    let data = merge_ranges(all_data);
+    trace!(
+        path, offset = src_range.start, len = full_length,
+        holes = full_length - data.iter().map(|r| r.end - r.start).sum::<u64>();
+        "Found {} data ranges to submit", data.len()
+    );
+    let chunks = data
+        .iter()
+        .flat_map(|r| {
+            (r.start..r.end)
+                .step_by(IOCTL_DEDUPE_MAX_BYTES as usize)
+                .map(move |start| start..u64::min(r.end, start + IOCTL_DEDUPE_MAX_BYTES))
+        })
+        .collect::<Vec<_>>();
+    // Destinations move back by as much as the source, to stay lined up with it. Ones that start
+    // too near the beginning of their file to move back that far can't be lined up, and are
+    // failed once rather than for every chunk.
+    let shift = |relative_start: u64| {
+        let chunk_start = src_range.start + relative_start;
+        chunk_start - align_down(chunk_start, block_size)
+    };
+    request.retain(|k, r| {
+        let Some(unaligned) = chunks
+            .iter()
+            .find(|c| r.dest_offset + c.start < shift(c.start))
+        else {
+            return true;
+        };
+        let response = DedupeResponse::Error(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "Destination offset can't be lined up with the source's block",
+        ));
+        trace!(
+            path = r.dest.to_string_lossy().as_ref(),
+            offset = r.dest_offset + unaligned.start, src = path;
+            "FIDEDUPERANGE destination {}", response
+        );
+        aggregate_results
+            .entry(k.clone())
+            .or_default()
+            .push(response);
+        false
+    });
+
+    'submit: for relative_chunk in chunks {
         for req_chunk in request
             .iter()
             .collect::<Vec<_>>()
             .chunks(IOCTL_DEDUPE_MAX_DESTS)
         {
+            let chunk_start = src_range.start + relative_chunk.start;
+            let chunk_end = src_range.start + relative_chunk.end;
+            let src_offset = align_down(chunk_start, block_size);
+            let shift = chunk_start - src_offset;
+            let chunk = DedupeChunk {
+                src_range: chunk_start..chunk_end,
+                dests: req_chunk.iter().map(|(k, _)| *k).collect(),
+            };
+            if !before_chunk(&chunk) {
+                break 'submit;
+            }
             let open_fds = req_chunk
                 .iter()
                 .map(|(_, r)| {
@@ -81,18 +179,15 @@ pub fn dedupe_files<K: Eq + Hash + Clone>(
                 .map(|(k, r)| (open_fds[&r.dest].as_raw_fd(), K::clone(k)))
                 .collect();
             SHARED_REQUEST.with_borrow_mut(|req| -> Result<(), std::io::Error> {
-                req.src_offset = align_down(src_range.start + offset, block_size);
-                req.src_length = u64::min(
-                    src_range.end - (src_range.start + offset),
-                    IOCTL_DEDUPE_MAX_BYTES,
-                );
+                req.src_offset = src_offset;
+                req.src_length = chunk_end - req.src_offset;
                 req.dest_count = req_chunk.len() as u16;
                 // Clear reserved fields just in case
                 req.reserved1 = 0;
                 req.reserved2 = 0;
                 for ((_, r), info) in req_chunk.iter().zip(req.info.iter_mut()) {
                     info.dest_fd = open_fds[&r.dest].as_raw_fd() as i64;
-                    info.dest_offset = align_down(r.dest_offset + offset, block_size);
+                    info.dest_offset = r.dest_offset + relative_chunk.start - shift;
                     // Purposefully throw junk in the return values
                     // That way, if for some reason they don't get filled, we know
                     info.bytes_deduped = u64::MAX;
@@ -100,9 +195,20 @@ pub fn dedupe_files<K: Eq + Hash + Clone>(
                     // Clear reserved fields just in case
                     info.reserved = 0;
                 }
-                ioctl(src, FIDEDUPERANGE, req)?;
+                if let Err(e) = ioctl(src, FIDEDUPERANGE, req) {
+                    trace!(
+                        path, offset = req.src_offset, len = req.src_length,
+                        dests = req_chunk.len(), errno = e.raw_os_error();
+                        "FIDEDUPERANGE failed: {}", e
+                    );
+                    return Err(e);
+                }
+                trace!(
+                    path, offset = req.src_offset, len = req.src_length, dests = req_chunk.len();
+                    "FIDEDUPERANGE submitted to {} destinations", req_chunk.len()
+                );
 
-                for info in &req.info[0..req_chunk.len()] {
+                for ((_, r), info) in req_chunk.iter().zip(&req.info[0..req_chunk.len()]) {
                     let response = match info.status {
                         errno if errno < 0 => {
                             DedupeResponse::Error(std::io::Error::from_raw_os_error(-errno))
@@ -121,6 +227,14 @@ pub fn dedupe_files<K: Eq + Hash + Clone>(
                         }
                         unknown => panic!("Unknown status from FIDEDUPERANGE ioctl: {}", unknown),
                     };
+                    trace!(
+                        path = r.dest.to_string_lossy().as_ref(), offset = info.dest_offset,
+                        len = req.src_length, src = path,
+                        errno = (info.status < 0).then_some(-info.status),
+                        bytes_deduped = (info.status == FILE_DEDUPE_RANGE_SAME)
+                            .then_some(info.bytes_deduped);
+                        "FIDEDUPERANGE {}", response
+                    );
                     aggregate_results
                         .entry(fd_map[&(info.dest_fd as RawFd)].clone())
                         .or_default()
@@ -130,13 +244,19 @@ pub fn dedupe_files<K: Eq + Hash + Clone>(
                 Ok(())
             })?;
         }
-
-        offset += IOCTL_DEDUPE_MAX_BYTES;
     }
 
     Ok(aggregate_results)
 }
 
+/// A single FIDEDUPERANGE submission, as seen by the `before_chunk` hook of [dedupe_files].
+pub struct DedupeChunk<'a, K> {
+    /// The source range being submitted.
+    pub src_range: Range<u64>,
+    /// The keys of the destinations that are part of this submission.
+    pub dests: Vec<&'a K>,
+}
+
 pub struct DedupeRequest {
     dest: PathBuf,
     dest_offset: u64,
@@ -154,7 +274,31 @@ impl DedupeRequest {
 pub enum DedupeResponse {
     Error(std::io::Error),
     RangeDiffers,
-    RangeSame { bytes_deduped: u64 },
+    RangeSame {
+        bytes_deduped: u64,
+    },
+    /// The destination has data in [range] where the source has a hole. It's still submitted,
+    /// so it's only de-duplicated if the data is all zeros.
+    SourceHole {
+        range: Range<u64>,
+    },
+}
+
+impl Display for DedupeResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DedupeResponse::Error(e) => write!(f, "failed: {}", e),
+            DedupeResponse::RangeDiffers => write!(f, "range differs"),
+            DedupeResponse::RangeSame { bytes_deduped } => {
+                write!(f, "de-duplicated {} bytes", bytes_deduped)
+            }
+            DedupeResponse::SourceHole { range } => write!(
+                f,
+                "has data where the source has a hole, at {}-{}",
+                range.start, range.end
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,58 +1,309 @@
 use std::collections::BTreeSet;
 use std::fmt::{Display, Formatter};
 use std::io::ErrorKind;
-use std::ops::Range;
+use std::mem::size_of;
+use std::ops::{BitOr, BitOrAssign, Range};
+use std::os::unix::fs::MetadataExt;
 
-use crate::ioctl::ioctl;
+use log::{debug, log_enabled, trace, Level};
+use serde::Serialize;
+
+use crate::ioctl::{file_path, ioctl_ptr};
 use crate::ioctl_consts::*;
 
-/// Get extents for a [file], approximately within the given [range].
-/// If [sync] is `true`, will set `FIEMAP_FLAG_SYNC`.
+/// Flags for a FIEMAP request, combined with `|`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct MapFlags(u32);
+
+impl MapFlags {
+    pub const NONE: MapFlags = MapFlags(0);
+    /// Write out dirty data first, so delayed allocations have a location by the time they're
+    /// mapped.
+    pub const SYNC: MapFlags = MapFlags(FIEMAP_FLAG_SYNC);
+    /// Map the blocks holding the extended attributes, instead of the file's data.
+    pub const XATTR: MapFlags = MapFlags(FIEMAP_FLAG_XATTR);
+    /// Have the filesystem load the whole extent tree into its cache. Only some filesystems,
+    /// such as ext4, support this.
+    pub const CACHE: MapFlags = MapFlags(FIEMAP_FLAG_CACHE);
+
+    pub fn contains(self, other: MapFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for MapFlags {
+    type Output = MapFlags;
+
+    fn bitor(self, rhs: MapFlags) -> MapFlags {
+        MapFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for MapFlags {
+    fn bitor_assign(&mut self, rhs: MapFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Get extents for a [file], approximately within the given [range], requested with [flags].
 /// Returns all extents that touch the given range, not just the ones strictly within it.
+/// A file that keeps changing while it's mapped is an [ErrorKind::InvalidData] error; use
+/// [map_extents] to tell that apart from other failures.
 pub fn get_extents(
     file: &std::fs::File,
     range: Range<u64>,
-    sync: bool,
+    flags: MapFlags,
 ) -> Result<Vec<Extent>, std::io::Error> {
-    let flags = if sync { FIEMAP_FLAG_SYNC } else { 0 };
+    match map_extents(file, range, flags)? {
+        ExtentMap::Complete(extents) => Ok(extents),
+        ExtentMap::Hole => Ok(Vec::new()),
+        ExtentMap::FileChanged => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "File changed while its extents were being read",
+        )),
+    }
+}
+
+/// What [map_extents] found.
+#[derive(Debug, Clone)]
+pub enum ExtentMap {
+    /// Every extent that touches the range, in order. Gaps between them, and after the last one,
+    /// are holes.
+    Complete(Vec<Extent>),
+    /// There's no data anywhere in the range, e.g. for an empty file, or a range past the end.
+    Hole,
+    /// The file kept changing while it was mapped, so nothing found can be trusted.
+    FileChanged,
+}
+
+/// Map the extents of [file] that touch [range], requested with [flags]. If the file changes
+/// while it's being mapped, it's tried once more with [MapFlags::SYNC], so that data still being
+/// written out is settled first.
+pub fn map_extents(
+    file: &std::fs::File,
+    range: Range<u64>,
+    flags: MapFlags,
+) -> Result<ExtentMap, std::io::Error> {
+    if let Some(map) = map_extents_once(file, range.clone(), flags)? {
+        return Ok(map);
+    }
+    if log_enabled!(Level::Debug) {
+        let path = file_path(file).display().to_string();
+        debug!(
+            path:% = path, offset = range.start, len = range.end - range.start;
+            "File changed while reading its extents, trying again after syncing it"
+        );
+    }
+    Ok(map_extents_once(file, range, flags | MapFlags::SYNC)?.unwrap_or(ExtentMap::FileChanged))
+}
+
+/// Map the extents touching [range], or return `None` if the file changed meanwhile.
+fn map_extents_once(
+    file: &std::fs::File,
+    range: Range<u64>,
+    flags: MapFlags,
+) -> Result<Option<ExtentMap>, std::io::Error> {
+    let before = FileVersion::of(file)?;
     let mut extents = Vec::<Extent>::new();
-    let mut offset: u64 = range.start;
-    while offset < range.end {
-        let mut request = FileExtentMapRequest::new(offset..range.end, flags);
+    for extent in ExtentIter::new(file, range, flags) {
+        let extent = match extent {
+            Ok(extent) => extent,
+            // The file changed between batches, so try again like below.
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<ExtentsMoved>()) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Batches are asked for from where the last one ended, so going backwards means the
+        // extents were moved around in between.
+        if let Some(last) = extents.last() {
+            if extent.logical_offset < last.logical_offset + last.length {
+                return Ok(None);
+            }
+        }
+        extents.push(extent);
+    }
+    if FileVersion::of(file)? != before {
+        return Ok(None);
+    }
+    Ok(Some(match extents.is_empty() {
+        true => ExtentMap::Hole,
+        false => ExtentMap::Complete(extents),
+    }))
+}
+
+/// The error [ExtentIter] gives when a batch is entirely before where it was asked to start from,
+/// which only happens when the file changed in between batches.
+#[derive(Debug)]
+struct ExtentsMoved;
+
+impl Display for ExtentsMoved {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FIEMAP returned extents before the range asked for")
+    }
+}
+
+impl std::error::Error for ExtentsMoved {}
+
+/// Enough of a file's metadata to notice it being written to or truncated.
+#[derive(PartialEq, Eq)]
+struct FileVersion {
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl FileVersion {
+    fn of(file: &std::fs::File) -> Result<FileVersion, std::io::Error> {
+        let metadata = file.metadata()?;
+        Ok(FileVersion {
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+}
 
-        ioctl(file, FS_IOC_FIEMAP, &mut request)?;
+/// How many extents are asked for at a time, unless [ExtentIter::batch_size] says otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 512;
 
-        let valid_extents: &[FileExtent] =
-            &request.fm_extents[0..(request.fm_mapped_extents as usize)];
-        for extent in valid_extents {
-            extents.push(Extent {
-                logical_offset: extent.fe_logical,
-                physical_offset: extent.fe_physical,
-                length: extent.fe_length,
-                flags: ExtentFlag::set_from(extent.fe_flags),
-            });
+/// Extents of a file, as in [get_extents], but only asking the kernel for the next batch once
+/// the previous one has been used up. Iteration stops after the first error, or at a batch
+/// without any extents, as the rest of the range is a hole. Nothing checks whether the file
+/// changed in between batches, unlike [map_extents].
+pub struct ExtentIter<'a> {
+    file: &'a std::fs::File,
+    range: Range<u64>,
+    flags: MapFlags,
+    /// The path of the file, if it's going to be logged.
+    path: String,
+    batch_size: usize,
+    request: Option<FileExtentMapRequest>,
+    /// The next extent in [request] to return.
+    next_index: usize,
+    /// Where the next batch starts from.
+    offset: u64,
+    done: bool,
+}
+
+impl<'a> ExtentIter<'a> {
+    pub fn new(file: &'a std::fs::File, range: Range<u64>, flags: MapFlags) -> ExtentIter<'a> {
+        // Only look up the path if it's going to be logged.
+        let path = if log_enabled!(Level::Debug) {
+            file_path(file).display().to_string()
+        } else {
+            String::new()
+        };
+        ExtentIter {
+            file,
+            offset: range.start,
+            range,
+            flags,
+            path,
+            batch_size: DEFAULT_BATCH_SIZE,
+            request: None,
+            next_index: 0,
+            done: false,
         }
+    }
+
+    /// Ask for up to [batch_size] extents at a time. Smaller batches use less memory, but take
+    /// more calls into the kernel.
+    pub fn batch_size(mut self, batch_size: usize) -> ExtentIter<'a> {
+        assert!(batch_size > 0, "batch size must be positive");
+        self.batch_size = batch_size;
+        self
+    }
 
-        if offset == 0 && valid_extents.is_empty() {
-            // empty file
-            return Ok(Vec::new());
+    /// Get the next batch of extents into [request], or `Ok(false)` if there are no more.
+    fn next_batch(&mut self) -> Result<bool, std::io::Error> {
+        if self.done || self.offset >= self.range.end {
+            return Ok(false);
         }
+        let path = self.path.as_str();
+        let (offset, end, flags) = (self.offset, self.range.end, self.flags.0);
+        let request = self
+            .request
+            .get_or_insert_with(|| FileExtentMapRequest::new(self.batch_size));
+        self.next_index = 0;
 
-        let last = valid_extents.last().ok_or_else(|| {
-            std::io::Error::new(
-                ErrorKind::InvalidData,
-                "File had no extents in range, probably changed while reading!",
-            )
-        })?;
+        if let Err(e) = request.submit(self.file, offset..end, flags) {
+            debug!(
+                path, offset, len = end - offset, flags, errno = e.raw_os_error();
+                "FIEMAP failed: {}", e
+            );
+            // The kernel hands back the flags it doesn't support.
+            if e.raw_os_error() == Some(libc::EBADR) {
+                return Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "Filesystem doesn't support FIEMAP flags {:#x}",
+                        request.header().fm_flags
+                    ),
+                ));
+            }
+            return Err(e);
+        }
+
+        let valid_extents = request.extents();
+        trace!(
+            path, offset, len = end - offset, extents = valid_extents.len();
+            "FIEMAP returned {} extents", valid_extents.len()
+        );
+
+        // Nothing left in the range but a hole, or the file is empty.
+        let Some(last) = valid_extents.last() else {
+            return Ok(false);
+        };
         if (last.fe_flags & FIEMAP_EXTENT_LAST) != 0 {
-            break;
+            self.done = true;
         }
         // Move offset to the end of the extent we just saw
-        offset = last.fe_logical + last.fe_length;
+        let last_end = last.fe_logical + last.fe_length;
+        if last_end <= offset {
+            // Returned extents always reach into the range, unless the file changed under us.
+            debug!(path, offset, len = end - offset; "FIEMAP returned extents before the range");
+            return Err(std::io::Error::new(ErrorKind::InvalidData, ExtentsMoved));
+        }
+        self.offset = last_end;
+        Ok(true)
     }
-    Ok(extents)
 }
 
+impl Iterator for ExtentIter<'_> {
+    type Item = Result<Extent, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(extent) = self
+                .request
+                .as_ref()
+                .and_then(|r| r.extents().get(self.next_index))
+            {
+                self.next_index += 1;
+                return Some(Ok(Extent {
+                    logical_offset: extent.fe_logical,
+                    physical_offset: extent.fe_physical,
+                    length: extent.fe_length,
+                    flags: ExtentFlag::set_from(extent.fe_flags),
+                }));
+            }
+            match self.next_batch() {
+                Ok(true) => continue,
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => {
+                    self.done = true;
+                    // Don't hand out what's left of the failed batch.
+                    self.request = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Extent {
     pub logical_offset: u64,
     pub physical_offset: u64,
@@ -60,7 +311,40 @@ pub struct Extent {
     pub flags: BTreeSet<ExtentFlag>,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+impl Extent {
+    /// The physical range this extent occupies, or `None` if it doesn't have a meaningful
+    /// location on disk (yet).
+    pub fn physical_range(&self) -> Option<Range<u64>> {
+        if self.flags.contains(&ExtentFlag::LocationUnknown)
+            || self.flags.contains(&ExtentFlag::DelayedAllocation)
+            || self.flags.contains(&ExtentFlag::DataInline)
+        {
+            return None;
+        }
+        Some(self.physical_offset..(self.physical_offset + self.length))
+    }
+
+    /// Cut this extent down to the part within the logical [range], if any of it is.
+    /// The physical offset is moved along with the logical offset, which is only accurate for
+    /// extents that aren't [ExtentFlag::Encoded].
+    pub fn clip(&self, range: Range<u64>) -> Option<Extent> {
+        let start = u64::max(self.logical_offset, range.start);
+        let end = u64::min(self.logical_offset + self.length, range.end);
+        if start >= end {
+            return None;
+        }
+        Some(Extent {
+            logical_offset: start,
+            physical_offset: self.physical_offset + (start - self.logical_offset),
+            length: end - start,
+            flags: self.flags.clone(),
+        })
+    }
+}
+
+/// Serialized in snake case, with unknown flags as `{"unknown": <bit>}`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ExtentFlag {
     Last,
     LocationUnknown,
@@ -144,29 +428,73 @@ impl ExtentFlag {
     }
 }
 
-const FILE_EXTENT_COUNT: usize = 512;
-
 #[repr(C)]
-struct FileExtentMapRequest {
+struct FileExtentMapHeader {
     fm_start: u64,
     fm_length: u64,
     fm_flags: u32,
     fm_mapped_extents: u32,
     fm_extent_count: u32,
     fm_reserved: u32,
-    fm_extents: [FileExtent; FILE_EXTENT_COUNT],
+}
+
+const HEADER_WORDS: usize = size_of::<FileExtentMapHeader>() / size_of::<u64>();
+const EXTENT_WORDS: usize = size_of::<FileExtent>() / size_of::<u64>();
+
+/// A FIEMAP request with room for a number of extents only known at runtime. The kernel expects
+/// the extents straight after the header, so both live in one buffer of `u64`s, which suits the
+/// alignment of either.
+struct FileExtentMapRequest {
+    buffer: Vec<u64>,
 }
 
 impl FileExtentMapRequest {
-    fn new(range: Range<u64>, flags: u32) -> FileExtentMapRequest {
-        FileExtentMapRequest {
-            fm_start: range.start,
-            fm_length: range.end - range.start,
-            fm_flags: flags,
-            fm_mapped_extents: 0,
-            fm_extent_count: FILE_EXTENT_COUNT as u32,
-            fm_reserved: 0,
-            fm_extents: [Default::default(); FILE_EXTENT_COUNT],
+    fn new(extent_count: usize) -> FileExtentMapRequest {
+        let mut request = FileExtentMapRequest {
+            buffer: vec![0; HEADER_WORDS + extent_count * EXTENT_WORDS],
+        };
+        request.header_mut().fm_extent_count = extent_count as u32;
+        request
+    }
+
+    fn header(&self) -> &FileExtentMapHeader {
+        // SAFETY: the buffer starts with a header, and is aligned for it.
+        unsafe { &*(self.buffer.as_ptr() as *const FileExtentMapHeader) }
+    }
+
+    fn header_mut(&mut self) -> &mut FileExtentMapHeader {
+        // SAFETY: as in header().
+        unsafe { &mut *(self.buffer.as_mut_ptr() as *mut FileExtentMapHeader) }
+    }
+
+    /// Map [range] of [file] with [flags], replacing the extents from the last call.
+    fn submit(
+        &mut self,
+        file: &std::fs::File,
+        range: Range<u64>,
+        flags: u32,
+    ) -> Result<(), std::io::Error> {
+        let header = self.header_mut();
+        header.fm_start = range.start;
+        header.fm_length = range.end - range.start;
+        header.fm_flags = flags;
+        header.fm_mapped_extents = 0;
+        header.fm_reserved = 0;
+        // SAFETY: the buffer is big enough for the extent count in the header.
+        unsafe { ioctl_ptr(file, FS_IOC_FIEMAP, self.buffer.as_mut_ptr().cast()) }
+    }
+
+    /// The extents filled in by the last call to [submit].
+    fn extents(&self) -> &[FileExtent] {
+        let header = self.header();
+        let count = u32::min(header.fm_mapped_extents, header.fm_extent_count) as usize;
+        // SAFETY: the extents follow the header, there's room for fm_extent_count of them, and
+        // the kernel filled in the first fm_mapped_extents.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.buffer.as_ptr().add(HEADER_WORDS) as *const FileExtent,
+                count,
+            )
         }
     }
 }
@@ -182,15 +510,186 @@ struct FileExtent {
     fe_reserved: [u32; 3],
 }
 
-impl Default for FileExtent {
-    fn default() -> Self {
-        FileExtent {
-            fe_logical: u64::MAX,
-            fe_physical: u64::MAX,
-            fe_length: u64::MAX,
-            fe_reserved64: [0; 2],
-            fe_flags: u32::MAX,
-            fe_reserved: [0; 3],
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::path::PathBuf;
+
+    use super::{map_extents, Extent, ExtentFlag, ExtentIter, ExtentMap, MapFlags};
+
+    const BLOCK: u64 = 4096;
+    /// Where [SparseFile] has data. Everything else is a hole.
+    const DATA: [(u64, u64); 3] = [(0, 2 * BLOCK), (16 * BLOCK, 2 * BLOCK), (32 * BLOCK, BLOCK)];
+
+    /// A file with [DATA] written and holes in between, removed when dropped.
+    struct SparseFile {
+        path: PathBuf,
+        file: File,
+    }
+
+    impl SparseFile {
+        /// Returns `None` if the temporary directory's filesystem doesn't support FIEMAP.
+        fn new(name: &str) -> Option<SparseFile> {
+            let path = std::env::temp_dir().join(format!(
+                "dedupetool-fiemap-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .unwrap();
+            let file = SparseFile { path, file };
+            for (offset, length) in DATA {
+                file.file
+                    .write_all_at(&vec![0xa5; length as usize], offset)
+                    .unwrap();
+            }
+            file.file.set_len(40 * BLOCK).unwrap();
+            file.file.sync_all().unwrap();
+            match map_extents(&file.file, 0..u64::MAX, MapFlags::NONE) {
+                Ok(_) => Some(file),
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => None,
+                Err(e) => panic!("FIEMAP failed: {}", e),
+            }
+        }
+    }
+
+    impl Drop for SparseFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn logical(extents: &[Extent]) -> Vec<(u64, u64)> {
+        extents
+            .iter()
+            .map(|e| (e.logical_offset, e.length))
+            .collect()
+    }
+
+    fn all(iter: ExtentIter) -> Vec<(u64, u64, u64, BTreeSet<ExtentFlag>)> {
+        iter.map(|e| e.unwrap())
+            .map(|e| (e.logical_offset, e.physical_offset, e.length, e.flags))
+            .collect()
+    }
+
+    fn extent(logical_offset: u64, physical_offset: u64, length: u64) -> Extent {
+        Extent {
+            logical_offset,
+            physical_offset,
+            length,
+            flags: BTreeSet::new(),
         }
     }
+
+    #[test]
+    fn small_batches_match_default() {
+        let Some(sparse) = SparseFile::new("batches") else {
+            return;
+        };
+        let file = &sparse.file;
+        let default = all(ExtentIter::new(file, 0..u64::MAX, MapFlags::NONE));
+        assert_eq!(
+            default
+                .iter()
+                .map(|(l, _, len, _)| (*l, *len))
+                .collect::<Vec<_>>(),
+            DATA
+        );
+        assert!(default.last().unwrap().3.contains(&ExtentFlag::Last));
+        for batch_size in [1, 2] {
+            let batched = ExtentIter::new(file, 0..u64::MAX, MapFlags::NONE).batch_size(batch_size);
+            assert_eq!(all(batched), default);
+        }
+    }
+
+    #[test]
+    fn take_one_reads_one_batch() {
+        let Some(sparse) = SparseFile::new("take") else {
+            return;
+        };
+        let mut iter = ExtentIter::new(&sparse.file, 0..u64::MAX, MapFlags::NONE).batch_size(1);
+        let first = iter
+            .by_ref()
+            .take(1)
+            .map(|e| e.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(logical(&first), vec![DATA[0]]);
+        // The next batch would start after the first extent, and hasn't been asked for yet.
+        assert_eq!(iter.offset, DATA[0].0 + DATA[0].1);
+        assert!(!iter.done);
+        assert_eq!(iter.count(), DATA.len() - 1);
+    }
+
+    #[test]
+    fn hole_only_range() {
+        let Some(sparse) = SparseFile::new("hole") else {
+            return;
+        };
+        let hole = (4 * BLOCK)..(12 * BLOCK);
+        assert!(matches!(
+            map_extents(&sparse.file, hole.clone(), MapFlags::NONE).unwrap(),
+            ExtentMap::Hole
+        ));
+        assert_eq!(
+            ExtentIter::new(&sparse.file, hole, MapFlags::NONE).count(),
+            0
+        );
+        // Past the end of the file.
+        assert!(matches!(
+            map_extents(&sparse.file, (64 * BLOCK)..(80 * BLOCK), MapFlags::NONE).unwrap(),
+            ExtentMap::Hole
+        ));
+    }
+
+    #[test]
+    fn range_cutting_into_extents() {
+        let Some(sparse) = SparseFile::new("range") else {
+            return;
+        };
+        let ExtentMap::Complete(whole) =
+            map_extents(&sparse.file, 0..u64::MAX, MapFlags::NONE).unwrap()
+        else {
+            panic!("expected extents");
+        };
+        // Cuts into the first extent and the second. Some filesystems return them whole, and
+        // some cut them down themselves, so they're compared once clipped.
+        let range = BLOCK..(17 * BLOCK);
+        let ExtentMap::Complete(extents) =
+            map_extents(&sparse.file, range.clone(), MapFlags::NONE).unwrap()
+        else {
+            panic!("expected extents");
+        };
+        let clipped = extents
+            .iter()
+            .filter_map(|e| e.clip(range.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(clipped.len(), extents.len());
+        assert_eq!(logical(&clipped), vec![(BLOCK, BLOCK), (16 * BLOCK, BLOCK)]);
+        assert_eq!(clipped[0].physical_offset, whole[0].physical_offset + BLOCK);
+        assert_eq!(clipped[1].physical_offset, whole[1].physical_offset);
+    }
+
+    #[test]
+    fn clip_edges() {
+        let e = extent(100, 1000, 50);
+        let clipped = |range| {
+            e.clip(range)
+                .map(|e| (e.logical_offset, e.physical_offset, e.length))
+        };
+        assert_eq!(clipped(0..200), Some((100, 1000, 50)));
+        assert_eq!(clipped(100..150), Some((100, 1000, 50)));
+        assert_eq!(clipped(110..200), Some((110, 1010, 40)));
+        assert_eq!(clipped(0..140), Some((100, 1000, 40)));
+        assert_eq!(clipped(110..120), Some((110, 1010, 10)));
+        // Touching either edge isn't overlapping.
+        assert_eq!(clipped(0..100), None);
+        assert_eq!(clipped(150..200), None);
+        assert_eq!(clipped(120..120), None);
+    }
 }
@@ -1,8 +1,14 @@
 #![deny(warnings)]
 
+pub mod device;
 pub mod diskblade;
 pub mod ioctl;
 pub mod ioctl_consts;
 pub mod ioctl_fideduperange;
 pub mod ioctl_fiemap;
+pub mod physical;
+pub mod sched;
+pub mod sparse;
 pub mod termhelp;
+pub mod throttle;
+pub mod unshare;
@@ -0,0 +1,418 @@
+//! Tracking of physical byte ranges, for working out how much space files really take up.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::ioctl_fiemap::Extent;
+
+/// A set of physical byte ranges. Overlapping and adjacent ranges are merged.
+#[derive(Debug, Clone, Default)]
+pub struct PhysicalRangeSet {
+    /// Map of range start to range end.
+    ranges: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl PhysicalRangeSet {
+    pub fn new() -> PhysicalRangeSet {
+        Default::default()
+    }
+
+    /// Add [range] to the set. Returns how many of its bytes weren't already in the set.
+    pub fn insert(&mut self, range: Range<u64>) -> u64 {
+        if range.is_empty() {
+            return 0;
+        }
+        let mut start = range.start;
+        let mut end = range.end;
+        let mut already_present = 0;
+        // Find everything that overlaps or touches the new range, and merge it in.
+        let touching: Vec<(u64, u64)> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in touching {
+            already_present += u64::min(e, range.end).saturating_sub(u64::max(s, range.start));
+            self.ranges.remove(&s);
+            start = u64::min(start, s);
+            end = u64::max(end, e);
+        }
+        self.ranges.insert(start, end);
+        let added = (range.end - range.start) - already_present;
+        self.total += added;
+        added
+    }
+
+    /// Returns how many bytes of [range] are in the set.
+    pub fn overlap(&self, range: Range<u64>) -> u64 {
+        self.ranges
+            .range(..range.end)
+            .rev()
+            .take_while(|(_, &e)| e > range.start)
+            .map(|(&s, &e)| u64::min(e, range.end).saturating_sub(u64::max(s, range.start)))
+            .sum()
+    }
+
+    /// The total number of bytes in the set.
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges.iter().map(|(&s, &e)| s..e)
+    }
+}
+
+/// Physical space used by a set of files.
+#[derive(Debug, Clone, Default)]
+pub struct PhysicalUsage {
+    /// The physical ranges used.
+    pub ranges: PhysicalRangeSet,
+    /// Bytes in extents without a usable physical location, e.g. delayed allocations.
+    /// These are assumed to not be shared with anything.
+    pub unlocated: u64,
+}
+
+impl PhysicalUsage {
+    pub fn new() -> PhysicalUsage {
+        Default::default()
+    }
+
+    /// Add the part of [extents] that is within the logical [range].
+    /// Returns how many bytes weren't already accounted for.
+    pub fn add_extents(&mut self, extents: &[Extent], range: Range<u64>) -> u64 {
+        let mut added = 0;
+        for extent in extents {
+            let Some(clipped) = extent.clip(range.clone()) else {
+                continue;
+            };
+            match clipped.physical_range() {
+                Some(physical) => added += self.ranges.insert(physical),
+                None => {
+                    self.unlocated += clipped.length;
+                    added += clipped.length;
+                }
+            }
+        }
+        added
+    }
+
+    /// The total bytes used.
+    pub fn total(&self) -> u64 {
+        self.ranges.len() + self.unlocated
+    }
+}
+
+/// Who uses each physical byte range, for finding what shares storage with what.
+#[derive(Debug, Clone)]
+pub struct PhysicalIndex<T> {
+    /// Map of extent start to the extents starting there.
+    extents: BTreeMap<u64, Vec<IndexedExtent<T>>>,
+    /// The longest extent, so lookups know how far back to look for overlapping ones.
+    longest: u64,
+}
+
+#[derive(Debug, Clone)]
+struct IndexedExtent<T> {
+    physical_end: u64,
+    logical_offset: u64,
+    owner: T,
+}
+
+/// Part of an indexed extent that overlaps a looked up range.
+#[derive(Debug, Clone)]
+pub struct PhysicalMapping<'a, T> {
+    pub owner: &'a T,
+    /// The overlapping physical range.
+    pub physical: Range<u64>,
+    /// Where the overlapping part is within the owner.
+    pub logical: Range<u64>,
+}
+
+impl<T> Default for PhysicalIndex<T> {
+    fn default() -> Self {
+        PhysicalIndex {
+            extents: BTreeMap::new(),
+            longest: 0,
+        }
+    }
+}
+
+impl<T> PhysicalIndex<T> {
+    pub fn new() -> PhysicalIndex<T> {
+        Default::default()
+    }
+
+    /// Add [extent], used by [owner]. Extents without a physical location are left out.
+    /// As with [Extent::clip], offsets within [ExtentFlag::Encoded] extents are only
+    /// approximate.
+    ///
+    /// [ExtentFlag::Encoded]: crate::ioctl_fiemap::ExtentFlag::Encoded
+    pub fn insert(&mut self, owner: T, extent: &Extent) {
+        let Some(physical) = extent.physical_range() else {
+            return;
+        };
+        if physical.is_empty() {
+            return;
+        }
+        self.longest = u64::max(self.longest, physical.end - physical.start);
+        self.extents
+            .entry(physical.start)
+            .or_default()
+            .push(IndexedExtent {
+                physical_end: physical.end,
+                logical_offset: extent.logical_offset,
+                owner,
+            });
+    }
+
+    /// Every indexed extent overlapping the physical [range], cut down to the overlap, in
+    /// physical order.
+    pub fn overlapping(&self, range: Range<u64>) -> impl Iterator<Item = PhysicalMapping<'_, T>> {
+        let from = range.start.saturating_sub(self.longest);
+        self.extents
+            .range(from..range.end.max(from))
+            .flat_map(|(&start, extents)| extents.iter().map(move |e| (start, e)))
+            .filter(move |(start, e)| {
+                u64::max(*start, range.start) < u64::min(e.physical_end, range.end)
+            })
+            .map(move |(start, e)| {
+                let physical = u64::max(start, range.start)..u64::min(e.physical_end, range.end);
+                let logical_start = e.logical_offset + (physical.start - start);
+                PhysicalMapping {
+                    owner: &e.owner,
+                    logical: logical_start..(logical_start + (physical.end - physical.start)),
+                    physical,
+                }
+            })
+    }
+
+    /// Split the indexed extents into the physical ranges where the same extents overlap, and
+    /// call [f] with each range and the owners of the extents covering it. Ranges nothing covers
+    /// are skipped. An owner is passed more than once if several of its extents cover the range.
+    pub fn for_each_segment(&self, mut f: impl FnMut(Range<u64>, &[&T])) {
+        let mut starts = self.extents.iter().peekable();
+        // The extents covering the current position, by where they end.
+        let mut active: Vec<(u64, &T)> = Vec::new();
+        let mut owners = Vec::new();
+        let mut position = 0;
+        loop {
+            let next_start = starts.peek().map(|(&start, _)| start);
+            let next_end = active.iter().map(|(end, _)| *end).min();
+            let next = match (next_start, next_end) {
+                (None, None) => break,
+                (Some(start), Some(end)) => u64::min(start, end),
+                (start, end) => start.or(end).unwrap(),
+            };
+            if !active.is_empty() && next > position {
+                owners.clear();
+                owners.extend(active.iter().map(|(_, owner)| *owner));
+                f(position..next, &owners);
+            }
+            position = next;
+            active.retain(|(end, _)| *end > position);
+            if next_start == Some(position) {
+                let (_, extents) = starts.next().unwrap();
+                active.extend(extents.iter().map(|e| (e.physical_end, &e.owner)));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extents.is_empty()
+    }
+}
+
+#[cfg(test)]
+// Single ranges are what's meant here, not a range of values.
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::ops::Range;
+
+    use crate::ioctl_fiemap::Extent;
+
+    use super::{PhysicalIndex, PhysicalRangeSet};
+
+    fn extent(logical_offset: u64, physical: Range<u64>) -> Extent {
+        Extent {
+            logical_offset,
+            physical_offset: physical.start,
+            length: physical.end - physical.start,
+            flags: BTreeSet::new(),
+        }
+    }
+
+    fn index(extents: &[(char, u64, Range<u64>)]) -> PhysicalIndex<char> {
+        let mut index = PhysicalIndex::new();
+        for (owner, logical_offset, physical) in extents {
+            index.insert(*owner, &extent(*logical_offset, physical.clone()));
+        }
+        index
+    }
+
+    fn range_set(ranges: &[Range<u64>]) -> PhysicalRangeSet {
+        let mut set = PhysicalRangeSet::new();
+        for range in ranges {
+            set.insert(range.clone());
+        }
+        set
+    }
+
+    #[test]
+    fn insert_disjoint() {
+        let mut set = PhysicalRangeSet::new();
+        assert_eq!(set.insert(20..30), 10);
+        assert_eq!(set.insert(0..10), 10);
+        assert_eq!(set.len(), 20);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..10, 20..30]);
+    }
+
+    #[test]
+    fn insert_adjacent() {
+        let mut set = range_set(&[0..10, 20..30]);
+        assert_eq!(set.insert(10..20), 10);
+        assert_eq!(set.len(), 30);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..30]);
+        assert_eq!(set.insert(30..35), 5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..35]);
+    }
+
+    #[test]
+    fn insert_contained() {
+        let mut set = range_set(&[0..30]);
+        assert_eq!(set.insert(10..20), 0);
+        assert_eq!(set.insert(0..30), 0);
+        assert_eq!(set.len(), 30);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..30]);
+
+        let mut set = range_set(&[10..20]);
+        assert_eq!(set.insert(0..30), 20);
+        assert_eq!(set.len(), 30);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..30]);
+    }
+
+    #[test]
+    fn insert_spanning_several() {
+        let mut set = range_set(&[0..10, 20..30, 40..50, 60..70]);
+        assert_eq!(set.insert(5..45), 20);
+        assert_eq!(set.len(), 60);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..50, 60..70]);
+    }
+
+    #[test]
+    fn insert_empty() {
+        let mut set = PhysicalRangeSet::new();
+        assert_eq!(set.insert(5..5), 0);
+        assert!(set.is_empty());
+        assert_eq!(set.iter().count(), 0);
+
+        let mut set = range_set(&[0..10]);
+        assert_eq!(set.insert(20..20), 0);
+        assert_eq!(set.len(), 10);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0..10]);
+    }
+
+    #[test]
+    fn overlap() {
+        let set = range_set(&[0..10, 20..30]);
+        assert_eq!(set.overlap(5..25), 10);
+        assert_eq!(set.overlap(0..30), 20);
+        assert_eq!(set.overlap(10..20), 0);
+        assert_eq!(set.overlap(12..18), 0);
+        assert_eq!(set.overlap(5..5), 0);
+        assert_eq!(PhysicalRangeSet::new().overlap(0..10), 0);
+    }
+
+    fn segments(index: &PhysicalIndex<char>) -> Vec<(Range<u64>, Vec<char>)> {
+        let mut segments = Vec::new();
+        index.for_each_segment(|range, owners| {
+            let mut owners = owners.iter().map(|o| **o).collect::<Vec<_>>();
+            owners.sort();
+            segments.push((range, owners));
+        });
+        segments
+    }
+
+    fn overlapping(
+        index: &PhysicalIndex<char>,
+        range: Range<u64>,
+    ) -> Vec<(char, Range<u64>, Range<u64>)> {
+        index
+            .overlapping(range)
+            .map(|m| (*m.owner, m.physical, m.logical))
+            .collect()
+    }
+
+    #[test]
+    fn segments_of_empty_index() {
+        let index = index(&[('a', 0, 10..10)]);
+        assert!(index.is_empty());
+        assert_eq!(segments(&index), vec![]);
+    }
+
+    #[test]
+    fn segments_of_adjacent_extents() {
+        let index = index(&[('a', 0, 0..10), ('b', 0, 10..20), ('c', 0, 30..40)]);
+        assert_eq!(
+            segments(&index),
+            vec![(0..10, vec!['a']), (10..20, vec!['b']), (30..40, vec!['c']),]
+        );
+    }
+
+    #[test]
+    fn segments_of_nested_extents() {
+        let index = index(&[('a', 0, 0..30), ('b', 0, 10..20), ('c', 0, 10..20)]);
+        assert_eq!(
+            segments(&index),
+            vec![
+                (0..10, vec!['a']),
+                (10..20, vec!['a', 'b', 'c']),
+                (20..30, vec!['a']),
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_of_overlapping_extents() {
+        let index = index(&[('a', 0, 0..20), ('b', 0, 10..30)]);
+        assert_eq!(
+            segments(&index),
+            vec![
+                (0..10, vec!['a']),
+                (10..20, vec!['a', 'b']),
+                (20..30, vec!['b']),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_skips_adjacent_extents() {
+        let index = index(&[('a', 0, 0..10), ('b', 100, 10..20), ('c', 0, 20..30)]);
+        assert_eq!(overlapping(&index, 10..20), vec![('b', 10..20, 100..110)]);
+    }
+
+    #[test]
+    fn overlapping_cuts_extents_to_the_range() {
+        // The long extent makes lookups look far back, past the short one before the range.
+        let index = index(&[('a', 0, 0..5), ('b', 100, 0..100), ('c', 50, 40..60)]);
+        assert_eq!(
+            overlapping(&index, 45..55),
+            vec![('b', 45..55, 145..155), ('c', 45..55, 55..65)]
+        );
+    }
+
+    #[test]
+    fn overlapping_empty_range() {
+        let index = index(&[('a', 0, 0..20)]);
+        assert_eq!(overlapping(&index, 10..10), vec![]);
+        assert_eq!(overlapping(&PhysicalIndex::new(), 0..10), vec![]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Helpers for running politely alongside other work: I/O and CPU priorities, and load checks.
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+/// An I/O scheduling class, see ioprio_set(2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// Only get disk time when no other program has asked for it.
+    Idle,
+    /// The normal class, at the given priority level from 0 (highest) to 7 (lowest).
+    BestEffort(u8),
+}
+
+impl IoClass {
+    fn ioprio(self) -> libc::c_int {
+        match self {
+            IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            IoClass::BestEffort(level) => {
+                assert!(level <= 7, "best-effort level must be in 0..=7");
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level)
+            }
+        }
+    }
+}
+
+/// Scheduling priorities to apply to every thread doing work.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Priority {
+    pub io_class: Option<IoClass>,
+    /// The nice value, from -20 (highest priority) to 19 (lowest).
+    pub cpu_nice: Option<i32>,
+}
+
+impl Priority {
+    /// Apply the priorities to the calling thread. On Linux both the I/O priority and the nice
+    /// value are per-thread, and are inherited by threads created afterwards.
+    pub fn apply_to_current_thread(&self) -> Result<(), std::io::Error> {
+        if let Some(io_class) = self.io_class {
+            if unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    io_class.ioprio(),
+                )
+            } == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(nice) = self.cpu_nice {
+            // `which` is a different integer type depending on the libc flavor.
+            #[allow(clippy::useless_conversion)]
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS.try_into().unwrap(), 0, nice) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the system load average over the last minute.
+pub fn load_average() -> Result<f64, std::io::Error> {
+    let mut loads = [0f64; 3];
+    if unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) } != 1 {
+        return Err(std::io::Error::other("failed to read the load average"));
+    }
+    Ok(loads[0])
+}
@@ -0,0 +1,209 @@
+//! Finding where sparse files have data, and where they have holes.
+
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// The parts of [range] in [file] that hold data, in order, found with `SEEK_DATA` and
+/// `SEEK_HOLE`. Filesystems that don't track holes report everything up to the end of the file
+/// as data.
+pub fn data_ranges(
+    file: &std::fs::File,
+    range: Range<u64>,
+) -> Result<Vec<Range<u64>>, std::io::Error> {
+    let mut ranges = Vec::new();
+    let mut offset = range.start;
+    while offset < range.end {
+        let data = match lseek(file, offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // There's no data after offset.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        if data >= range.end {
+            break;
+        }
+        // There's always a hole at the end of the file.
+        let hole = lseek(file, data, libc::SEEK_HOLE)?;
+        ranges.push(data..u64::min(hole, range.end));
+        offset = hole;
+    }
+    Ok(ranges)
+}
+
+/// The parts of [ranges] that aren't in [remove]. Both must be sorted and not overlap
+/// themselves, as from [data_ranges].
+pub fn subtract_ranges(ranges: &[Range<u64>], remove: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut result = Vec::new();
+    let mut remove = remove.iter().peekable();
+    for range in ranges {
+        let mut start = range.start;
+        // Skip what ends before this range. It can't overlap later ones either.
+        while remove.next_if(|r| r.end <= start).is_some() {}
+        for r in remove.clone() {
+            if r.start >= range.end {
+                break;
+            }
+            if r.is_empty() {
+                continue;
+            }
+            if r.start > start {
+                result.push(start..r.start);
+            }
+            start = u64::max(start, r.end);
+        }
+        if start < range.end {
+            result.push(start..range.end);
+        }
+    }
+    result
+}
+
+/// Sort [ranges] and merge the ones that overlap or touch.
+pub fn merge_ranges(ranges: impl IntoIterator<Item = Range<u64>>) -> Vec<Range<u64>> {
+    let mut ranges = ranges
+        .into_iter()
+        .filter(|r| !r.is_empty())
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = u64::max(last.end, range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// How much is read at a time when looking for zeros.
+const ZERO_SCAN_BUFFER: u64 = 1024 * 1024;
+
+/// Find the whole blocks of [block_size] bytes within [range] of [file] that are all zeros,
+/// merged into runs. Only blocks that fit entirely in [range] are considered.
+///
+/// [before_read] is called with the length of each read before it's made. If it returns
+/// `false`, the scan stops and the runs found so far are returned.
+pub fn zero_ranges<F: FnMut(u64) -> bool>(
+    file: &std::fs::File,
+    range: Range<u64>,
+    block_size: u64,
+    mut before_read: F,
+) -> Result<Vec<Range<u64>>, std::io::Error> {
+    let start = range.start.div_ceil(block_size) * block_size;
+    let end = range.end / block_size * block_size;
+    let buffer_len = u64::max(block_size, ZERO_SCAN_BUFFER / block_size * block_size);
+    let mut buffer = vec![0u8; buffer_len as usize];
+    let mut zeros = Vec::<Range<u64>>::new();
+    let mut offset = start;
+    while offset < end {
+        let len = u64::min(buffer_len, end - offset);
+        if !before_read(len) {
+            break;
+        }
+        let buffer = &mut buffer[..len as usize];
+        file.read_exact_at(buffer, offset)?;
+        for (i, block) in buffer.chunks(block_size as usize).enumerate() {
+            if block.iter().any(|&b| b != 0) {
+                continue;
+            }
+            let block_start = offset + i as u64 * block_size;
+            match zeros.last_mut() {
+                Some(last) if last.end == block_start => last.end += block_size,
+                _ => zeros.push(block_start..(block_start + block_size)),
+            }
+        }
+        offset += len;
+    }
+    Ok(zeros)
+}
+
+/// Deallocate [range] of [file], keeping its size. It reads back as zeros afterwards.
+pub fn punch_hole(file: &std::fs::File, range: Range<u64>) -> Result<(), std::io::Error> {
+    let offset = libc::off_t::try_from(range.start)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Offset too large"))?;
+    let len = libc::off_t::try_from(range.end - range.start)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Length too large"))?;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn lseek(file: &std::fs::File, offset: u64, whence: libc::c_int) -> Result<u64, std::io::Error> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Offset too large"))?;
+    match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        position => Ok(position as u64),
+    }
+}
+
+#[cfg(test)]
+// Single ranges are what's meant here, not a range of values.
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::{merge_ranges, subtract_ranges};
+
+    #[test]
+    fn subtract_empty() {
+        assert_eq!(subtract_ranges(&[], &[0..10]), vec![]);
+        assert_eq!(subtract_ranges(&[0..10], &[]), vec![0..10]);
+        assert_eq!(subtract_ranges(&[0..10], &[5..5]), vec![0..10]);
+    }
+
+    #[test]
+    fn subtract_adjacent() {
+        assert_eq!(
+            subtract_ranges(&[10..20, 30..40], &[0..10, 20..30, 40..50]),
+            vec![10..20, 30..40]
+        );
+    }
+
+    #[test]
+    fn subtract_nested() {
+        assert_eq!(subtract_ranges(&[0..30], &[10..20]), vec![0..10, 20..30]);
+        assert_eq!(subtract_ranges(&[10..20], &[0..30]), vec![]);
+        assert_eq!(subtract_ranges(&[0..10], &[0..10]), vec![]);
+    }
+
+    #[test]
+    fn subtract_overlapping() {
+        assert_eq!(subtract_ranges(&[0..20], &[10..30]), vec![0..10]);
+        assert_eq!(subtract_ranges(&[10..30], &[0..20]), vec![20..30]);
+        // One removed range across several, and several within one.
+        assert_eq!(
+            subtract_ranges(&[0..10, 20..30, 40..50], &[5..45]),
+            vec![0..5, 45..50]
+        );
+        assert_eq!(
+            subtract_ranges(&[0..50], &[5..10, 20..30, 45..60]),
+            vec![0..5, 10..20, 30..45]
+        );
+    }
+
+    #[test]
+    fn merge_empty() {
+        assert_eq!(merge_ranges([]), vec![]);
+        assert_eq!(merge_ranges([5..5, 0..0]), vec![]);
+        assert_eq!(merge_ranges([0..10, 20..20]), vec![0..10]);
+    }
+
+    #[test]
+    fn merge_adjacent() {
+        assert_eq!(merge_ranges([10..20, 0..10, 30..40]), vec![0..20, 30..40]);
+    }
+
+    #[test]
+    fn merge_nested() {
+        assert_eq!(merge_ranges([0..30, 10..20]), vec![0..30]);
+        assert_eq!(merge_ranges([10..20, 0..30, 10..20]), vec![0..30]);
+    }
+
+    #[test]
+    fn merge_overlapping() {
+        assert_eq!(merge_ranges([20..40, 0..25, 35..50]), vec![0..50]);
+    }
+}
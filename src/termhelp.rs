@@ -47,6 +47,8 @@ pub trait DedupetoolProgressBar {
     }
 
     fn dedupetool_spinner(item_name: &str) -> Self;
+
+    fn dedupetool_bytes_bar() -> Self;
 }
 
 impl DedupetoolProgressBar for ProgressBar {
@@ -80,4 +82,15 @@ impl DedupetoolProgressBar for ProgressBar {
         );
         bar
     }
+
+    fn dedupetool_bytes_bar() -> Self {
+        let bar = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{percent:>3}%[{bar:30.cyan/blue}] {binary_bytes}/{binary_total_bytes} {wide_msg}")
+                .unwrap()
+                .progress_chars("#|-"),
+        );
+        bar
+    }
 }
@@ -0,0 +1,150 @@
+//! Token-bucket rate limiting for I/O submitted to the kernel.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::device::BlockDevice;
+
+/// How long to sleep at a time while waiting on a bucket, between checks for being stopped.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+/// A token bucket that refills at a fixed rate, holding at most one second worth of tokens.
+///
+/// Acquiring more tokens than are available puts the bucket into debt, and the caller sleeps
+/// until the debt is paid off. This lets requests larger than the bucket through, while still
+/// keeping the average rate in check.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a bucket that refills at [rate] tokens per second. It starts out full.
+    pub fn new(rate: u64) -> TokenBucket {
+        assert!(rate > 0, "rate must be positive");
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new(TokenBucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take [amount] tokens from the bucket, blocking the current thread until they are paid for.
+    /// Gives up waiting and returns `false` once [stop] returns `true`, which is checked every
+    /// [SLEEP_SLICE].
+    pub fn acquire<F: Fn() -> bool>(&self, amount: u64, stop: F) -> bool {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.last_refill).as_secs_f64() * self.rate;
+            state.tokens = f64::min(state.tokens + refill, self.rate);
+            state.last_refill = now;
+            state.tokens -= amount as f64;
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        let until = Instant::now() + wait;
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                return true;
+            }
+            if stop() {
+                return false;
+            }
+            std::thread::sleep(Duration::min(until - now, SLEEP_SLICE));
+        }
+    }
+}
+
+/// A set of limits on the I/O submitted to the kernel, shared by all tasks.
+#[derive(Default)]
+pub struct Throttle {
+    /// Limits bytes per second across all devices.
+    bytes: Option<TokenBucket>,
+    /// Limits ioctls per second.
+    operations: Option<TokenBucket>,
+    /// Limits bytes per second for individual devices, keyed by block device ID.
+    device_bytes: HashMap<u64, TokenBucket>,
+    /// Block device IDs by filesystem device ID, as resolving them reads `/proc`.
+    block_devices: Mutex<HashMap<u64, u64>>,
+}
+
+impl Throttle {
+    /// Device IDs in [device_bytes_per_second] may be of a block device or a filesystem, and are
+    /// resolved to the block device backing them.
+    pub fn new(
+        bytes_per_second: Option<u64>,
+        operations_per_second: Option<u64>,
+        device_bytes_per_second: HashMap<u64, u64>,
+    ) -> Throttle {
+        Throttle {
+            bytes: bytes_per_second.map(TokenBucket::new),
+            operations: operations_per_second.map(TokenBucket::new),
+            device_bytes: device_bytes_per_second
+                .into_iter()
+                .map(|(dev, rate)| (BlockDevice::resolve(dev).id, TokenBucket::new(rate)))
+                .collect(),
+            block_devices: Default::default(),
+        }
+    }
+
+    /// Returns `true` if this throttle never blocks.
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes.is_none() && self.operations.is_none() && self.device_bytes.is_empty()
+    }
+
+    /// Charge a single operation that reads [reads], a list of `(device ID, byte count)` pairs,
+    /// where the device IDs are the `st_dev` of the files read.
+    /// Blocks until every applicable limit allows it, or returns `false` early once [stop]
+    /// returns `true`.
+    pub fn charge<F: Fn() -> bool>(&self, reads: &[(u64, u64)], stop: F) -> bool {
+        if let Some(operations) = &self.operations {
+            if !operations.acquire(1, &stop) {
+                return false;
+            }
+        }
+        if let Some(bytes) = &self.bytes {
+            if !bytes.acquire(reads.iter().map(|(_, len)| len).sum(), &stop) {
+                return false;
+            }
+        }
+        if self.device_bytes.is_empty() {
+            return true;
+        }
+        let mut per_device = HashMap::<u64, u64>::new();
+        for (dev, len) in reads {
+            *per_device.entry(self.block_device(*dev)).or_default() += len;
+        }
+        for (dev, len) in per_device {
+            if let Some(bucket) = self.device_bytes.get(&dev) {
+                if !bucket.acquire(len, &stop) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// The block device backing the filesystem with device ID [st_dev]. On filesystems like
+    /// btrfs, `st_dev` is an anonymous ID that never matches the block device's.
+    fn block_device(&self, st_dev: u64) -> u64 {
+        *self
+            .block_devices
+            .lock()
+            .unwrap()
+            .entry(st_dev)
+            .or_insert_with(|| BlockDevice::resolve(st_dev).id)
+    }
+}
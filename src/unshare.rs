@@ -0,0 +1,258 @@
+//! Breaking the sharing of extents, so a file has storage of its own.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::ioctl::{file_path, ioctl};
+use crate::ioctl_consts::*;
+use crate::sparse::data_ranges;
+
+/// How much is copied at a time by [copy_and_replace].
+const COPY_BUFFER: usize = 1024 * 1024;
+
+/// The inode flags, as set by `chattr`, that [copy_and_replace] keeps. Immutable and append-only
+/// files can't be replaced, and the rest are managed by the filesystem.
+const COPIED_FLAGS: i32 =
+    FS_SYNC_FL | FS_NODUMP_FL | FS_NOATIME_FL | FS_COMPR_FL | FS_NOCOMP_FL | FS_NOCOW_FL;
+
+/// Give [range] of [file] storage of its own, with `FALLOC_FL_UNSHARE_RANGE`. Fails with
+/// `EOPNOTSUPP` on filesystems that don't support it.
+pub fn unshare_range(file: &File, range: Range<u64>) -> Result<(), std::io::Error> {
+    let offset = libc::off_t::try_from(range.start)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Offset too large"))?;
+    let len = libc::off_t::try_from(range.end - range.start)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Length too large"))?;
+    let mode = libc::FALLOC_FL_UNSHARE_RANGE;
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding [file].
+pub fn available_space(file: &File) -> Result<u64, std::io::Error> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// Whether the filesystem holding [file] can unshare in place with [unshare_range]. Only XFS is
+/// known to, so unsharing on others, such as btrfs, falls back to [copy_and_replace].
+pub fn unshares_in_place(file: &File) -> Result<bool, std::io::Error> {
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_type as u64 == libc::XFS_SUPER_MAGIC as u64)
+}
+
+/// Replace the file at [path] with a copy of itself, written with plain reads and writes so it
+/// doesn't share any storage. Holes are kept, as are the permissions, owner, extended attributes,
+/// `chattr` flags like `+C`, and access and modification times.
+///
+/// The copy is a new inode, so anything that has the file open keeps the old one. Renaming would
+/// separate hard links, so files with more than one fail with `InvalidInput`.
+/// If the file changes while it's being copied, it's left alone and this fails with
+/// `InvalidData`.
+pub fn copy_and_replace(path: &Path) -> Result<(), std::io::Error> {
+    let source = File::open(path)?;
+    let metadata = source.metadata()?;
+    if metadata.nlink() > 1 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "File has other hard links, which replacing it would separate",
+        ));
+    }
+    let temp_path = temp_path(path)?;
+    let temp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    let result = copy_flags(&source, &temp).and_then(|_| {
+        copy_contents(&source, &temp, &metadata)?;
+        let current = source.metadata()?;
+        if (current.size(), current.mtime(), current.mtime_nsec())
+            != (metadata.size(), metadata.mtime(), metadata.mtime_nsec())
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "File changed while it was being copied",
+            ));
+        }
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// A name next to [path] to copy it to.
+fn temp_path(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "Path has no file name"))?;
+    let mut temp_name = OsStr::new(".").to_os_string();
+    temp_name.push(name);
+    temp_name.push(format!(".dedupetool-unshare.{}", std::process::id()));
+    Ok(path.with_file_name(temp_name))
+}
+
+fn copy_contents(
+    source: &File,
+    dest: &File,
+    metadata: &std::fs::Metadata,
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![0u8; COPY_BUFFER];
+    for range in data_ranges(source, 0..metadata.size())? {
+        let mut offset = range.start;
+        while offset < range.end {
+            let len = u64::min(buffer.len() as u64, range.end - offset) as usize;
+            let buffer = &mut buffer[..len];
+            source.read_exact_at(buffer, offset)?;
+            dest.write_all_at(buffer, offset)?;
+            offset += len as u64;
+        }
+    }
+    dest.set_len(metadata.size())?;
+    copy_xattrs(source, dest)?;
+
+    let fd = dest.as_raw_fd();
+    // Only root can give files away, so don't try unless it's needed.
+    let dest_metadata = dest.metadata()?;
+    if (dest_metadata.uid(), dest_metadata.gid()) != (metadata.uid(), metadata.gid())
+        && unsafe { libc::fchown(fd, metadata.uid(), metadata.gid()) } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    // After changing the owner, which clears the setuid and setgid bits.
+    if unsafe { libc::fchmod(fd, metadata.mode() & 0o7777) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    if unsafe { libc::futimens(fd, times.as_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copy the [COPIED_FLAGS] from [source] to [dest]. Done while [dest] is empty, as btrfs only
+/// lets `+C` be changed then. Filesystems without inode flags have nothing to copy.
+fn copy_flags(source: &File, dest: &File) -> Result<(), std::io::Error> {
+    let mut flags: libc::c_int = 0;
+    match ioctl(source, FS_IOC_GETFLAGS, &mut flags) {
+        Ok(()) => {}
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP)) => {
+            return Ok(())
+        }
+        Err(e) => return Err(e),
+    }
+    let mut dest_flags: libc::c_int = 0;
+    ioctl(dest, FS_IOC_GETFLAGS, &mut dest_flags)?;
+    let mut wanted = (dest_flags & !COPIED_FLAGS) | (flags & COPIED_FLAGS);
+    if wanted != dest_flags {
+        ioctl(dest, FS_IOC_SETFLAGS, &mut wanted)?;
+    }
+    Ok(())
+}
+
+/// Copy every extended attribute, including ACLs, from [source] to [dest]. `security.*` and
+/// `trusted.*` attributes that we aren't allowed to copy, like SELinux labels without
+/// privileges, are left for the filesystem to set, with a warning.
+fn copy_xattrs(source: &File, dest: &File) -> Result<(), std::io::Error> {
+    let names = read_xattr(|buf, len| unsafe {
+        libc::flistxattr(source.as_raw_fd(), buf as *mut libc::c_char, len)
+    })?;
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = CString::new(name).expect("xattr names can't contain NUL");
+        let result = read_xattr(|buf, len| unsafe {
+            libc::fgetxattr(source.as_raw_fd(), name.as_ptr(), buf, len)
+        })
+        .and_then(|value| set_xattr(dest, &name, &value));
+        match result {
+            Ok(()) => {}
+            Err(e)
+                if matches!(e.raw_os_error(), Some(libc::EPERM | libc::ENOTSUP))
+                    && (name.to_bytes().starts_with(b"security.")
+                        || name.to_bytes().starts_with(b"trusted.")) =>
+            {
+                warn!(
+                    path:% = file_path(source).display(), xattr:% = name.to_string_lossy(),
+                    errno = e.raw_os_error();
+                    "Not copying extended attribute {}: {}", name.to_string_lossy(), e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Call [get] to find the size needed, then to fill a buffer of that size. Retries if it grew in
+/// between.
+fn read_xattr<F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t>(
+    get: F,
+) -> Result<Vec<u8>, std::io::Error> {
+    loop {
+        let len = get(std::ptr::null_mut(), 0);
+        if len == -1 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        let mut buffer = vec![0u8; len as usize];
+        match get(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) {
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::ERANGE) {
+                    return Err(e);
+                }
+            }
+            len => {
+                buffer.truncate(len as usize);
+                return Ok(buffer);
+            }
+        }
+    }
+}
+
+fn set_xattr(file: &File, name: &CStr, value: &[u8]) -> Result<(), std::io::Error> {
+    let result = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}